@@ -0,0 +1,226 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{bail, Context, Result};
+
+use super::git::*;
+use super::manifest::*;
+use super::patch::{commit_message, source_commit};
+use super::render::{do_render, remove_file, RenderedTemplate};
+use super::schema::*;
+use super::*;
+
+pub(super) fn apply(args: ApplyArgs) -> Result<()> {
+    let cfg = Config::load(&args.config)?;
+    let target = cfg.target(&args.repo, args.target_branch.as_deref())?;
+    let (repo, dir) = (target.repo, target.dir());
+    let rendered = do_render(&args.config, &cfg)?;
+    let git = backend();
+
+    // rendered files, and files from the previous render that have been
+    // dropped
+    let mut files: BTreeMap<&str, Option<&RenderedTemplate>> = BTreeMap::new();
+    for (path, result) in &rendered {
+        if result.target == dir {
+            let path = result
+                .relative(path)?
+                .to_str()
+                .with_context(|| format!("bad path {}", path.display()))?;
+            files.insert(path, Some(result));
+        }
+    }
+    let manifest = Manifest::load(&args.into)?.unwrap_or_default();
+    for path in manifest.files.keys() {
+        files.entry(path).or_insert(None);
+    }
+
+    // find files that need updating
+    let mut changed = Vec::new();
+    let mut templates = BTreeSet::new();
+    for (path, result) in &files {
+        let target = args.into.join(path);
+        let needed = match result {
+            Some(result) => !result.matches(&target)?,
+            None => target.exists(),
+        };
+        if needed {
+            changed.push(*path);
+            // the manifest records the templates of dropped files
+            templates.extend(match result {
+                Some(result) => result.template.as_deref(),
+                None => Some(manifest.files[*path].template.as_str()),
+            });
+        }
+    }
+    if changed.is_empty() {
+        eprintln!("{} is up to date", args.into.display());
+        return Ok(());
+    }
+
+    // don't clobber local changes, including files we'd be creating
+    if !args.force {
+        let status = git
+            .status(&args.into)
+            .with_context(|| format!("getting status of {}", args.into.display()))?;
+        // status paths are relative to the repo root, which may be above
+        // the checkout we're updating
+        let dirty: BTreeSet<&str> = status
+            .changed
+            .iter()
+            .chain(&status.untracked)
+            .filter_map(|path| path.strip_prefix(&status.prefix))
+            .collect();
+        let conflicts: Vec<&str> = changed
+            .iter()
+            .filter(|path| dirty.contains(*path))
+            .copied()
+            .collect();
+        if !conflicts.is_empty() {
+            bail!(
+                "uncommitted changes to {}; use --force to overwrite",
+                conflicts.join(", ")
+            );
+        }
+    }
+
+    if let Some(branch) = &args.branch {
+        git.create_branch(&args.into, branch)
+            .with_context(|| format!("creating branch {}", branch))?;
+    }
+    for path in &changed {
+        let target = args.into.join(path);
+        match files[path] {
+            Some(result) => {
+                eprintln!("Writing {}", path);
+                result.write(&target)?;
+            }
+            None => {
+                eprintln!("Removing {}", path);
+                remove_file(&target)?;
+            }
+        }
+    }
+    if args.branch.is_some() {
+        let message = commit_message(
+            source_commit(git.as_ref(), &args.config).as_ref(),
+            repo.location().ok().as_ref(),
+            &templates,
+        );
+        git.commit(&args.into, &changed, &message, args.author.as_ref())
+            .context("committing changes")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_AUTHOR_NAME", "Test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "Test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn apply_conflicts() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = temp.path().join("config.yaml");
+        let write_config = |templates: &str| {
+            fs::write(
+                &config,
+                format!(
+                    "templates: [{}]\nrepos:\n  x: {{url: https://example.com/o/x}}\n",
+                    templates
+                ),
+            )
+            .unwrap()
+        };
+        write_config("a.txt, b.txt");
+        for name in ["a", "b"] {
+            fs::write(
+                temp.path().join(name).with_extension("yaml"),
+                format!("files:\n  - repo: x\n    path: {}.txt\n", name),
+            )
+            .unwrap();
+            fs::write(temp.path().join(name).with_extension("txt"), name).unwrap();
+        }
+
+        // the checkout to update is a subdirectory of the work tree
+        let repo = temp.path().join("repo");
+        let into = repo.join("sub");
+        fs::create_dir_all(&into).unwrap();
+        git(&repo, &["init", "-b", "main"]);
+        fs::write(into.join("README"), "readme").unwrap();
+        git(&repo, &["add", "."]);
+        git(&repo, &["commit", "-m", "init"]);
+
+        let args = |branch: Option<&str>| ApplyArgs {
+            config: config.clone(),
+            repo: "x".into(),
+            into: into.clone(),
+            target_branch: None,
+            force: false,
+            branch: branch.map(String::from),
+            author: Some("A U Thor <author@example.com>".parse().unwrap()),
+        };
+
+        // untracked files are local changes too
+        fs::write(into.join("a.txt"), "local").unwrap();
+        assert_eq!(
+            apply(args(None)).unwrap_err().to_string(),
+            "uncommitted changes to a.txt; use --force to overwrite"
+        );
+        fs::remove_file(into.join("a.txt")).unwrap();
+
+        apply(args(Some("add"))).unwrap();
+        assert_eq!(fs::read_to_string(into.join("b.txt")).unwrap(), "b");
+        assert_eq!(
+            git(&repo, &["ls-files"]),
+            "sub/.github/repo-templates.json\nsub/README\nsub/a.txt\nsub/b.txt\n"
+        );
+
+        // as are modified tracked files
+        fs::write(into.join("b.txt"), "local").unwrap();
+        write_config("a.txt");
+        assert_eq!(
+            apply(args(None)).unwrap_err().to_string(),
+            "uncommitted changes to b.txt; use --force to overwrite"
+        );
+        git(&repo, &["checkout", "sub/b.txt"]);
+
+        // deleted files are credited to their template
+        apply(args(Some("remove"))).unwrap();
+        assert!(!into.join("b.txt").exists());
+        let message = git(&repo, &["log", "-1", "--format=%B"]);
+        assert!(
+            message.contains("Changed templates:\n- b.txt\n"),
+            "{}",
+            message
+        );
+    }
+}
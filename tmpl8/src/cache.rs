@@ -12,161 +12,601 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
 use std::fs;
 use std::io;
-use std::os::fd::{AsFd, IntoRawFd};
-use std::os::unix::io::FromRawFd;
+use std::num::NonZeroUsize;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
 
 use anyhow::{bail, Context, Result};
 use filetime::{self, FileTime};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::unistd::{access, AccessFlags};
+use sha2::{Digest, Sha256};
 
+use super::git::*;
+use super::manifest::*;
+use super::render::{render_managed_paths, warn};
 use super::schema::*;
 use super::*;
 
-const DEFAULT_BRANCH: &str = "DEFAULT";
+/// Stamp recording the branch a cached repo is tracking
+pub(super) const STAMP_PATH: &str = ".git/tmpl8-stamp";
+
+/// List of paths included in the sparse checkout
+pub(super) const SPARSE_PATH: &str = ".git/tmpl8-sparse";
+
+/// Subdirectory holding clones that always track the upstream default
+/// branch
+pub(super) const UPSTREAM_DIR: &str = ".upstream";
+
+/// Advisory lock serializing access to the cache
+const LOCK_PATH: &str = ".lock";
+
+/// Target directory -> paths within the repo branch that we manage
+pub(super) type ManagedPaths = BTreeMap<String, BTreeSet<String>>;
 
 pub(super) fn update_cache(args: UpdateCacheArgs) -> Result<()> {
-    let cfg = Config::parse(&args.config)?;
-    do_update_cache(&cfg, &cache_dir(&args.config)?, &args.fork, true)
+    let cfg = Config::load(&args.config)?;
+    let cache_dir = cache_dir(&args.config, &args.cache)?;
+    let paths = render_managed_paths(&args.config, &cfg)?;
+    let _lock = lock_cache(&cache_dir, true)?;
+    do_update_cache(&cfg, &cache_dir, args.update.jobs, None, &args.fork, &paths)
 }
 
+/// Update the cached repos, skipping those updated within `max_age`
+/// seconds.  If `max_age` is `None`, update all of them.
 pub(super) fn do_update_cache(
     cfg: &Config,
     cache_dir: &Path,
+    jobs: NonZeroUsize,
+    max_age: Option<u32>,
     fork: &ForkArgs,
-    force: bool,
+    paths: &ManagedPaths,
 ) -> Result<()> {
-    for (name, repo) in &cfg.repos {
-        // clone repo if missing
-        let path = cache_dir.join(name);
-        if !path.exists() {
-            run_command(
-                Command::new("git")
-                    .args(["clone", "--depth=1", &repo.url])
-                    .arg(&path)
-                    .stdout(stderr()?),
-            )?;
-            // use consistent name for default branch
-            run_command(
-                Command::new("git")
-                    .args(["branch", "-m", DEFAULT_BRANCH])
-                    .stdout(stderr()?)
-                    .current_dir(&path),
-            )?;
+    // update repo branches in parallel, collecting failures
+    let git = backend();
+    let targets = cfg.targets()?;
+    let queue = Mutex::new(targets.iter());
+    let failures = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs.get().min(targets.len()) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let target = match next {
+                    Some(v) => v,
+                    None => break,
+                };
+                let name = target.dir();
+                let paths = paths.get(&name).cloned().unwrap_or_default();
+                if let Err(e) = update_repo(git.as_ref(), target, cache_dir, max_age, fork, &paths)
+                {
+                    failures.lock().unwrap().push((name, e));
+                }
+            });
         }
+    });
 
-        // compute unique identifier of remote branch
-        let remote_url = fork
-            .regex
-            .as_ref()
-            .map(|re| re.replace(&repo.url, fork.replacement.as_ref().unwrap()));
-        let ident = if let Some(url) = &remote_url {
-            format!("{} {}\n", url, fork.branch.as_ref().unwrap())
-        } else {
-            DEFAULT_BRANCH.into()
-        };
+    let mut failures = failures.into_inner().unwrap();
+    if failures.is_empty() {
+        return Ok(());
+    }
+    failures.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, e) in &failures {
+        eprintln!("{}: {:#}", name, e);
+    }
+    bail!(
+        "failed to update {}",
+        failures
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+}
+
+fn update_repo(
+    git: &dyn GitBackend,
+    target: &Target,
+    cache_dir: &Path,
+    max_age: Option<u32>,
+    fork: &ForkArgs,
+    paths: &BTreeSet<String>,
+) -> Result<()> {
+    // clone repo if missing, checking out only the managed files
+    let name = &target.dir();
+    let path = cache_dir.join(name);
+    if !path.exists() {
+        let mut sparse = paths.clone();
+        sparse.insert(MANIFEST_PATH.into());
+        git.clone_default(name, &target.repo.url, target.branch_name(), &path, &sparse)?;
+        write_sparse(&path, &sparse)?;
+    }
+
+    // see if we need to update
+    let (remote, ident) = tracking(target, fork)?;
+    let stamp_path = path.join(STAMP_PATH);
+    // need to switch branches if the stamp contents are different
+    if let Some((id, age)) = read_stamp(&stamp_path)? {
+        // update anyway if stale or forced
+        let fresh = max_age.is_some_and(|max_age| (0..max_age as i64).contains(&age));
+        if id == ident.as_bytes() && fresh {
+            // the set of managed files may have changed anyway
+            return update_sparse(git, name, &path, paths);
+        }
+    }
+
+    // update checkout
+    let mut updated = false;
+    if let Some((remote_url, remote_branch)) = &remote {
+        match git.checkout_remote(name, &path, remote_url, remote_branch) {
+            Ok(()) => updated = true,
+            // no pending fork branch; fall back to default branch
+            Err(GitError::RepoMissing(_)) | Err(GitError::BranchMissing(..)) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if !updated {
+        git.checkout_default(name, &path)?;
+    }
+    update_sparse(git, name, &path, paths)?;
+
+    // update stamp
+    fs::write(&stamp_path, &ident).with_context(|| format!("writing {}", stamp_path.display()))
+}
 
-        // see if we need to update
-        let stamp_path = path.join(".git/tmpl8-stamp");
-        // need to switch branches if the stamp contents are different
-        match fs::read(&stamp_path) {
-            Ok(id) if id == ident.as_bytes() => {
-                // update anyway if stale or forced
-                let meta = fs::metadata(&stamp_path)
-                    .with_context(|| format!("statting {}", stamp_path.display()))?;
-                // Update the cache at most once per hour, unless forced
-                let age = FileTime::now().seconds()
-                    - FileTime::from_last_modification_time(&meta).seconds();
-                if (0..3600).contains(&age) && !force {
-                    continue;
+pub(super) fn status(args: CacheStatusArgs) -> Result<()> {
+    let cfg = Config::load(&args.config)?;
+    let cache_dir = cache_dir(&args.config, &args.cache)?;
+    let _lock = lock_cache(&cache_dir, false)?;
+    let git = backend();
+
+    // the upstream clones used by diff --base are tracked separately, and
+    // only exist if something has used them
+    let upstream_prefix = format!("{}/", UPSTREAM_DIR);
+    let mut rows = Vec::new();
+    for (dir, prefix) in [
+        (cache_dir.clone(), ""),
+        (cache_dir.join(UPSTREAM_DIR), upstream_prefix.as_str()),
+    ] {
+        for target in cfg.targets()? {
+            let name = target.dir();
+            let path = dir.join(&name);
+            let name = format!("{}{}", prefix, name);
+            if !path.exists() {
+                if prefix.is_empty() {
+                    rows.push([name, "-".into(), "-".into(), "-".into(), "missing".into()]);
                 }
+                continue;
             }
-            Ok(_) => (),
-            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
-            Err(e) => return Err(e).with_context(|| format!("reading {}", stamp_path.display())),
+            let (tracking, age) = match read_stamp(&path.join(STAMP_PATH))? {
+                Some((id, age)) => (describe_stamp(&id, &target), format_age(age)),
+                None => ("-".into(), "-".into()),
+            };
+            let (head, state) = match git.status(&path) {
+                Ok(status) => (
+                    status.head.chars().take(12).collect(),
+                    if !status.has_default {
+                        "broken"
+                    } else if !status.changed.is_empty() {
+                        "dirty"
+                    } else {
+                        "clean"
+                    },
+                ),
+                Err(_) => ("-".into(), "broken"),
+            };
+            rows.push([name, tracking, head, age, state.into()]);
+        }
+        for name in unconfigured(&cfg, &dir)? {
+            rows.push([
+                format!("{}{}", prefix, name),
+                "-".into(),
+                "-".into(),
+                "-".into(),
+                "unconfigured".into(),
+            ]);
         }
+    }
 
-        // update checkout
-        let mut updated = false;
-        // remote fork branch exists?
-        if let Some(remote_url) = &remote_url {
-            if Command::new("git")
-                .args(&[
-                    "fetch",
-                    "--depth",
-                    "1",
-                    remote_url,
-                    fork.branch.as_ref().unwrap(),
-                ])
-                // disable password prompts so we don't block if the repo is
-                // missing
-                .env("GIT_ASKPASS", "/bin/true")
-                .stdout(stderr()?)
-                .current_dir(&path)
-                .status()
-                .context("running git fetch")?
-                .success()
-            {
-                run_command(
-                    Command::new("git")
-                        .args(&["-c", "advice.detachedHead=false", "checkout", "FETCH_HEAD"])
-                        .stdout(stderr()?)
-                        .current_dir(&path),
-                )?;
-                updated = true;
-            }
+    let header = ["REPO", "TRACKING", "HEAD", "AGE", "STATE"];
+    let mut widths = header.map(|h| h.len());
+    for row in &rows {
+        for (width, col) in widths.iter_mut().zip(row) {
+            *width = (*width).max(col.len());
         }
-        // fall back to default branch
-        if !updated {
-            run_command(
-                Command::new("git")
-                    .args(&["checkout", DEFAULT_BRANCH])
-                    .stdout(stderr()?)
-                    .current_dir(&path),
-            )?;
-            run_command(
-                Command::new("git")
-                    .arg("pull")
-                    .stdout(stderr()?)
-                    .current_dir(&path),
-            )?;
+    }
+    for row in [header.map(String::from)].iter().chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(col, width)| format!("{:width$}", col))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+pub(super) fn gc(args: CacheGcArgs) -> Result<()> {
+    let cfg = Config::load(&args.config)?;
+    let cache_dir = cache_dir(&args.config, &args.cache)?;
+    let paths = render_managed_paths(&args.config, &cfg)?;
+    let _lock = lock_cache(&cache_dir, true)?;
+    let git = backend();
+
+    // the upstream clones used by diff --base are tracked separately
+    let upstream_fork = ForkArgs::default();
+    let upstream_prefix = format!("{}/", UPSTREAM_DIR);
+    for (dir, prefix, fork) in [
+        (cache_dir.clone(), "", &args.fork),
+        (
+            cache_dir.join(UPSTREAM_DIR),
+            upstream_prefix.as_str(),
+            &upstream_fork,
+        ),
+    ] {
+        // remove clones of repos that are no longer configured
+        for name in unconfigured(&cfg, &dir)? {
+            eprintln!("Removing {}{}", prefix, name);
+            let path = dir.join(&name);
+            fs::remove_dir_all(&path).with_context(|| format!("removing {}", path.display()))?;
         }
 
-        // update stamp
-        fs::write(&stamp_path, &ident)
-            .with_context(|| format!("writing {}", stamp_path.display()))?;
+        // re-clone repos with a missing stamp or default branch
+        for target in cfg.targets()? {
+            let name = target.dir();
+            let path = dir.join(&name);
+            if !path.exists() {
+                continue;
+            }
+            let broken = read_stamp(&path.join(STAMP_PATH))?.is_none()
+                || !git.status(&path).map(|s| s.has_default).unwrap_or(false);
+            if broken {
+                eprintln!("Repairing {}{}", prefix, name);
+                fs::remove_dir_all(&path)
+                    .with_context(|| format!("removing {}", path.display()))?;
+                update_repo(
+                    git.as_ref(),
+                    &target,
+                    &dir,
+                    None,
+                    fork,
+                    &paths.get(&name).cloned().unwrap_or_default(),
+                )
+                .with_context(|| format!("repairing {}", name))?;
+            }
+        }
     }
     Ok(())
 }
 
-pub(super) fn cache_dir(config_path: &Path) -> Result<PathBuf> {
-    Ok(config_path
-        .parent()
-        .with_context(|| format!("getting parent of {}", config_path.display()))?
-        .join(".cache"))
+/// Names of clones in the cache that don't correspond to a configured repo
+/// branch
+fn unconfigured(cfg: &Config, cache_dir: &Path) -> Result<Vec<String>> {
+    let configured: BTreeSet<String> = cfg.targets()?.iter().map(|t| t.dir()).collect();
+    let entries = match fs::read_dir(cache_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", cache_dir.display())),
+    };
+    let mut ret = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("reading {}", cache_dir.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // only consider clones we created, in case the cache dir is shared
+        if !configured.contains(&name) && entry.path().join(STAMP_PATH).is_file() {
+            ret.push(name);
+        }
+    }
+    ret.sort();
+    Ok(ret)
 }
 
-fn run_command(cmd: &mut Command) -> Result<()> {
-    let desc = format!(
-        "{} {}",
-        cmd.get_program().to_string_lossy(),
-        cmd.get_args()
-            .map(|v| v.to_string_lossy())
-            .collect::<Vec<_>>()
-            .join(" ")
-    );
-    let status = cmd
-        .status()
-        .with_context(|| format!("running '{}'", desc))?;
-    if !status.success() {
-        bail!("command failed: '{}'", desc);
+/// Human-readable form of a stamp
+fn describe_stamp(id: &[u8], target: &Target) -> String {
+    let id = String::from_utf8_lossy(id);
+    if id == DEFAULT_BRANCH {
+        match target.branch_name() {
+            Some(branch) => branch.into(),
+            None => "default branch".into(),
+        }
+    } else {
+        id.trim().to_string()
+    }
+}
+
+/// Warn about cached repos that are missing, stale, or tracking a
+/// different branch than requested, without updating anything.  Fail if
+/// a cached repo doesn't check out all of the managed files, since they
+/// would look missing.
+pub(super) fn check_offline(
+    cfg: &Config,
+    cache_dir: &Path,
+    max_age: u32,
+    fork: &ForkArgs,
+    paths: &ManagedPaths,
+) -> Result<()> {
+    for warning in offline_warnings(cfg, cache_dir, max_age, fork, paths)? {
+        warn(warning);
     }
     Ok(())
 }
 
-fn stderr() -> Result<Stdio> {
-    let stderr_fd = nix::unistd::dup(io::stderr().as_fd()).context("duplicating stderr")?;
-    Ok(unsafe { Stdio::from_raw_fd(stderr_fd.into_raw_fd()) })
+fn offline_warnings(
+    cfg: &Config,
+    cache_dir: &Path,
+    max_age: u32,
+    fork: &ForkArgs,
+    paths: &ManagedPaths,
+) -> Result<Vec<String>> {
+    let mut ret = Vec::new();
+    for target in cfg.targets()? {
+        let name = target.dir();
+        let path = cache_dir.join(&name);
+        let warning = if !path.exists() {
+            "not cached; comparing against an empty repo".into()
+        } else {
+            // without a record, the checkout isn't sparse
+            let sparse = read_sparse(&path)?;
+            if let Some(missing) = paths
+                .get(&name)
+                .into_iter()
+                .flatten()
+                .find(|p| sparse.as_ref().is_some_and(|s| !s.contains(*p)))
+            {
+                bail!(
+                    "{}: cache does not cover {}; rerun without --offline",
+                    name,
+                    missing
+                );
+            }
+            match read_stamp(&path.join(STAMP_PATH))? {
+                None => "cache has no stamp; contents may be out of date".into(),
+                Some((id, _)) if id != tracking(&target, fork)?.1.as_bytes() => {
+                    "cache is tracking a different branch".into()
+                }
+                Some((_, age)) if !(0..max_age as i64).contains(&age) => {
+                    format!("cache was last updated {} ago", format_age(age))
+                }
+                Some(_) => continue,
+            }
+        };
+        ret.push(format!("{}: {}", name, warning));
+    }
+    Ok(ret)
+}
+
+/// Update the sparse checkout to include the managed files, plus any files
+/// listed in the checked-out manifest, so we can see which files were
+/// dropped from the templates.
+fn update_sparse(
+    git: &dyn GitBackend,
+    name: &str,
+    path: &Path,
+    paths: &BTreeSet<String>,
+) -> Result<()> {
+    let mut sparse = paths.clone();
+    sparse.insert(MANIFEST_PATH.into());
+    if let Some(manifest) = Manifest::load(path)? {
+        sparse.extend(manifest.files.into_keys());
+    }
+    if read_sparse(path)?.as_ref() == Some(&sparse) {
+        return Ok(());
+    }
+    git.set_sparse(name, path, &sparse)?;
+    write_sparse(path, &sparse)
+}
+
+/// Read the recorded sparse checkout paths, if any.
+fn read_sparse(path: &Path) -> Result<Option<BTreeSet<String>>> {
+    let record_path = path.join(SPARSE_PATH);
+    match fs::read_to_string(&record_path) {
+        Ok(data) => Ok(Some(data.lines().map(String::from).collect())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {}", record_path.display())),
+    }
+}
+
+fn write_sparse(path: &Path, sparse: &BTreeSet<String>) -> Result<()> {
+    let record_path = path.join(SPARSE_PATH);
+    let data: String = sparse.iter().map(|p| format!("{}\n", p)).collect();
+    fs::write(&record_path, data).with_context(|| format!("writing {}", record_path.display()))
+}
+
+/// Return the fork URL and branch to check out, if any, and the stamp
+/// identifying the branch the cache should be tracking.
+fn tracking(target: &Target, fork: &ForkArgs) -> Result<(Option<(String, String)>, String)> {
+    let remote = fork
+        .url(target.repo)?
+        .map(|url| (url, fork.branch_for(target).unwrap()));
+    let ident = if let Some((url, branch)) = &remote {
+        format!("{} {}\n", url, branch)
+    } else {
+        DEFAULT_BRANCH.into()
+    };
+    Ok((remote, ident))
+}
+
+/// Read the stamp contents and its age in seconds, returning None if
+/// missing.
+fn read_stamp(stamp_path: &Path) -> Result<Option<(Vec<u8>, i64)>> {
+    let id = match fs::read(stamp_path) {
+        Ok(id) => id,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", stamp_path.display())),
+    };
+    let meta =
+        fs::metadata(stamp_path).with_context(|| format!("statting {}", stamp_path.display()))?;
+    let age = FileTime::now().seconds() - FileTime::from_last_modification_time(&meta).seconds();
+    Ok(Some((id, age)))
+}
+
+fn format_age(secs: i64) -> String {
+    match secs {
+        s if s < 3600 => format!("{} minutes", s / 60),
+        s if s < 2 * 86400 => format!("{} hours", s / 3600),
+        s => format!("{} days", s / 86400),
+    }
+}
+
+/// Take the cache lock, waiting if another process holds it.  Exclusive
+/// locks are needed to modify the cache.
+pub(super) fn lock_cache(cache_dir: &Path, exclusive: bool) -> Result<Flock<fs::File>> {
+    fs::create_dir_all(cache_dir).with_context(|| format!("creating {}", cache_dir.display()))?;
+    let path = cache_dir.join(LOCK_PATH);
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    let (nonblock, block) = if exclusive {
+        (FlockArg::LockExclusiveNonblock, FlockArg::LockExclusive)
+    } else {
+        (FlockArg::LockSharedNonblock, FlockArg::LockShared)
+    };
+    let file = match Flock::lock(file, nonblock) {
+        Ok(lock) => return Ok(lock),
+        Err((file, Errno::EWOULDBLOCK)) => file,
+        Err((_, e)) => return Err(e).with_context(|| format!("locking {}", path.display())),
+    };
+    eprintln!("Waiting for lock on {}", path.display());
+    Flock::lock(file, block)
+        .map_err(|(_, e)| e)
+        .with_context(|| format!("locking {}", path.display()))
+}
+
+/// Use the directory from the command line or environment, if any.
+/// Otherwise, use `.cache` next to the config file, or a directory under
+/// the XDG cache directory specific to this config file if the config
+/// directory is read-only.
+pub(super) fn cache_dir(config_path: &Path, args: &CacheArgs) -> Result<PathBuf> {
+    if let Some(dir) = &args.cache_dir {
+        return Ok(dir.clone());
+    }
+    let config_dir = Config::dir(config_path);
+    let dir = config_dir.join(".cache");
+    let writable = access(config_dir, AccessFlags::W_OK).is_ok();
+    if writable || dir.exists() {
+        return Ok(dir);
+    }
+    let xdg = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME").context("HOME is not set")?).join(".cache"),
+    };
+    // configs sharing the XDG cache shouldn't step on each other's clones
+    let canonical = fs::canonicalize(config_path)
+        .with_context(|| format!("canonicalizing {}", config_path.display()))?;
+    let id = format!("{:x}", Sha256::digest(canonical.as_os_str().as_bytes()));
+    Ok(xdg.join("tmpl8").join(&id[..16]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unconfigured_clones() {
+        let cfg: Config = serde_yaml::from_str(
+            "
+templates: []
+repos:
+  a: {url: https://example.com/a}
+",
+        )
+        .unwrap();
+        let temp = tempfile::tempdir().unwrap();
+        // configured clone, stale clone, someone else's checkout, and a
+        // plain directory
+        for name in ["a", "b", "other", "plain"] {
+            fs::create_dir_all(temp.path().join(name).join(".git")).unwrap();
+        }
+        fs::remove_dir(temp.path().join("plain/.git")).unwrap();
+        for name in ["a", "b"] {
+            fs::write(temp.path().join(name).join(STAMP_PATH), DEFAULT_BRANCH).unwrap();
+        }
+        assert_eq!(unconfigured(&cfg, temp.path()).unwrap(), vec!["b"]);
+        assert!(unconfigured(&cfg, &temp.path().join("missing"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn offline_staleness() {
+        let cfg: Config = serde_yaml::from_str(
+            "
+templates: []
+repos:
+  fresh: {url: https://example.com/fresh}
+  missing: {url: https://example.com/missing}
+  unstamped: {url: https://example.com/unstamped}
+  stale: {url: https://example.com/stale}
+  forked: {url: https://example.com/forked}
+",
+        )
+        .unwrap();
+        let temp = tempfile::tempdir().unwrap();
+        for name in ["fresh", "unstamped", "stale", "forked"] {
+            fs::create_dir_all(temp.path().join(name).join(".git")).unwrap();
+        }
+        for name in ["fresh", "stale"] {
+            fs::write(temp.path().join(name).join(STAMP_PATH), DEFAULT_BRANCH).unwrap();
+        }
+        fs::write(
+            temp.path().join("forked").join(STAMP_PATH),
+            "https://example.com/fork/forked branch\n",
+        )
+        .unwrap();
+        let two_days_ago = FileTime::from_unix_time(FileTime::now().seconds() - 2 * 86400, 0);
+        filetime::set_file_mtime(temp.path().join("stale").join(STAMP_PATH), two_days_ago).unwrap();
+
+        assert_eq!(
+            offline_warnings(
+                &cfg,
+                temp.path(),
+                3600,
+                &ForkArgs::default(),
+                &ManagedPaths::new()
+            )
+            .unwrap(),
+            [
+                "forked: cache is tracking a different branch",
+                "missing: not cached; comparing against an empty repo",
+                "stale: cache was last updated 2 days ago",
+                "unstamped: cache has no stamp; contents may be out of date",
+            ]
+        );
+    }
+
+    #[test]
+    fn offline_coverage() {
+        let cfg: Config = serde_yaml::from_str(
+            "
+templates: []
+repos:
+  a: {url: https://example.com/a}
+",
+        )
+        .unwrap();
+        let temp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(temp.path().join("a/.git")).unwrap();
+        fs::write(temp.path().join("a").join(STAMP_PATH), DEFAULT_BRANCH).unwrap();
+        let sparse = ["README.md".into(), MANIFEST_PATH.into()].into();
+        write_sparse(&temp.path().join("a"), &sparse).unwrap();
+        let check = |files: &[&str]| {
+            let paths = [("a".into(), files.iter().map(|f| f.to_string()).collect())].into();
+            check_offline(&cfg, temp.path(), u32::MAX, &ForkArgs::default(), &paths)
+        };
+        check(&["README.md"]).unwrap();
+        assert_eq!(
+            check(&["README.md", "new.md"]).unwrap_err().to_string(),
+            "a: cache does not cover new.md; rerun without --offline"
+        );
+    }
 }
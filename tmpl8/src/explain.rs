@@ -0,0 +1,231 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+
+use super::render::{target_context, template_config_path};
+use super::schema::*;
+use super::*;
+
+/// One source of context variables
+struct Layer<'a> {
+    desc: String,
+    vars: &'a Vars,
+}
+
+pub(super) fn explain_vars(args: ExplainVarsArgs) -> Result<()> {
+    print!("{}", explain(&args)?);
+    Ok(())
+}
+
+/// Describe the variables for each of the template's files in the repo.
+fn explain(args: &ExplainVarsArgs) -> Result<String> {
+    let cfg = Config::load(&args.config)?;
+    let target = cfg.target(&args.repo, args.target_branch.as_deref())?;
+    if !cfg.templates.contains(&args.template) {
+        bail!("no such template: {}", args.template);
+    }
+    let tmpl_cfg_path = template_config_path(&args.config, &args.template)?;
+    let tmpl_cfg = TemplateConfig::load(&tmpl_cfg_path, &cfg)?;
+
+    let files: Vec<&File> = tmpl_cfg
+        .files
+        .iter()
+        .filter(|f| f.repo == args.repo)
+        .collect();
+    if files.is_empty() {
+        bail!(
+            "template {} has no files for repo {}",
+            args.template,
+            args.repo
+        );
+    }
+
+    let defaults = tmpl_cfg.defaults();
+    let no_vars = Vars::default();
+    let mut ret = String::new();
+    for (i, file) in files.iter().enumerate() {
+        let ctx = target_context(
+            &cfg,
+            &target,
+            &tmpl_cfg.vars,
+            &file.vars,
+            &defaults.to_context()?,
+        )
+        .with_context(|| format!("resolving variables for {}", file.path().display()))?;
+        // lowest to highest precedence, matching do_render()
+        let mut layers = vec![Layer {
+            desc: format!("schema defaults in {}", tmpl_cfg_path.display()),
+            vars: &defaults,
+        }];
+        layers.extend(target.presets().map(|(name, preset)| Layer {
+            desc: format!(
+                "computed defaults of preset {} in {}",
+                name,
+                args.config.display()
+            ),
+            vars: &preset.defaults,
+        }));
+        // included files can't redefine a var, so their order doesn't matter
+        layers.extend(cfg.var_files.iter().map(|(path, vars)| Layer {
+            desc: format!("global vars in {}", path.display()),
+            vars,
+        }));
+        layers.push(Layer {
+            desc: format!("template vars in {}", tmpl_cfg_path.display()),
+            vars: &tmpl_cfg.vars,
+        });
+        layers.extend(target.presets().map(|(name, preset)| Layer {
+            desc: format!("preset {} vars in {}", name, args.config.display()),
+            vars: &preset.vars,
+        }));
+        let config_path = target.repo.source.as_deref().unwrap_or(&args.config);
+        layers.extend([
+            Layer {
+                desc: format!("repo vars in {}", config_path.display()),
+                vars: &target.repo.vars,
+            },
+            Layer {
+                desc: format!(
+                    "branch {} vars in {}",
+                    target.branch_name().unwrap_or_default(),
+                    config_path.display()
+                ),
+                vars: target.branch.map(|b| &b.vars).unwrap_or(&no_vars),
+            },
+            Layer {
+                desc: format!("file vars in {}", tmpl_cfg_path.display()),
+                vars: &file.vars,
+            },
+        ]);
+        if i > 0 {
+            ret.push('\n');
+        }
+        writeln!(ret, "# {}", file.path().display())?;
+        describe_layers(&mut ret, &layers, &tmpl_cfg.schema, &ctx)?;
+    }
+    Ok(ret)
+}
+
+/// ctx: the resolved variables, for showing the values of templated ones
+fn describe_layers(
+    out: &mut String,
+    layers: &[Layer],
+    schema: &BTreeMap<String, VarSchema>,
+    ctx: &tera::Context,
+) -> Result<()> {
+    // variable name -> list of (layer, value), highest precedence first
+    let mut sources: BTreeMap<&str, Vec<(&Layer, &serde_yaml::Value)>> = BTreeMap::new();
+    for layer in layers.iter().rev() {
+        for (name, value) in layer.vars.iter() {
+            sources.entry(name).or_default().push((layer, value));
+        }
+    }
+    for (name, sources) in sources {
+        for (i, (layer, value)) in sources.into_iter().enumerate() {
+            let value = serde_json::to_string(value).context("serializing value")?;
+            if i == 0 {
+                let resolved = match ctx.get(name) {
+                    Some(v) => serde_json::to_string(v).context("serializing value")?,
+                    None => value.clone(),
+                };
+                writeln!(out, "{} = {}", name, resolved)?;
+                if resolved != value {
+                    writeln!(out, "    rendered from {}", value)?;
+                }
+                if let Some(desc) = schema.get(name).and_then(|d| d.description.as_ref()) {
+                    writeln!(out, "    ({})", desc)?;
+                }
+                writeln!(out, "    from {}", layer.desc)?;
+            } else {
+                writeln!(out, "    shadows {} from {}", value, layer.desc)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn provenance() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = temp.path().join("config.yaml");
+        fs::write(
+            &config,
+            "
+vars: {org: coreos, stream: stable}
+presets:
+  rust:
+    defaults: {package: 'rust-{{ crate }}'}
+    vars: {lang: rust}
+repos:
+  a: {url: https://example.com/a, presets: [rust], vars: {crate: a, stream: next}}
+templates: [t.md]
+",
+        )
+        .unwrap();
+        fs::write(
+            temp.path().join("t.yaml"),
+            "
+vars: {stream: testing}
+schema:
+  image: {type: string, default: quay.io/coreos/a, description: Container image}
+files:
+  - repo: a
+    path: t.md
+    vars: {org: fedora}
+",
+        )
+        .unwrap();
+        fs::write(temp.path().join("t.md"), "").unwrap();
+
+        let out = explain(&ExplainVarsArgs {
+            config,
+            repo: "a".into(),
+            target_branch: None,
+            template: "t.md".into(),
+        })
+        .unwrap()
+        .replace(&format!("{}/", temp.path().display()), "");
+        assert_eq!(
+            out,
+            r#"# a/t.md
+crate = "a"
+    from repo vars in config.yaml
+image = "quay.io/coreos/a"
+    (Container image)
+    from schema defaults in t.yaml
+lang = "rust"
+    from preset rust vars in config.yaml
+org = "fedora"
+    from file vars in t.yaml
+    shadows "coreos" from global vars in config.yaml
+package = "rust-a"
+    rendered from "rust-{{ crate }}"
+    from computed defaults of preset rust in config.yaml
+stream = "next"
+    from repo vars in config.yaml
+    shadows "testing" from template vars in t.yaml
+    shadows "stable" from global vars in config.yaml
+"#
+        );
+    }
+}
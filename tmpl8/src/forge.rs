@@ -0,0 +1,216 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use super::schema::*;
+use super::*;
+
+/// Hosting service for a repo, which determines how its URLs are laid out
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Forge {
+    /// GitHub
+    Github,
+    /// GitLab
+    Gitlab,
+    /// Any other host; only the owner and name are known
+    Generic,
+}
+
+impl Forge {
+    /// Guess the forge from a well-known hostname
+    fn infer(host: &str) -> Option<Self> {
+        match host {
+            "github.com" => Some(Self::Github),
+            "gitlab.com" => Some(Self::Gitlab),
+            _ => None,
+        }
+    }
+}
+
+/// A repo URL split into its parts
+#[derive(Debug, PartialEq, Eq)]
+pub struct Location {
+    pub forge: Forge,
+    pub host: String,
+    /// User, organization, or group path; may be empty on generic forges
+    pub owner: String,
+    pub name: String,
+}
+
+impl Location {
+    /// Parse an HTTPS repo URL.  If forge is None, infer it from the host.
+    pub fn parse(url: &str, forge: Option<Forge>) -> Result<Self> {
+        let rest = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))
+            .with_context(|| format!("unsupported URL {}", url))?;
+        let rest = rest.trim_end_matches('/');
+        let rest = rest.strip_suffix(".git").unwrap_or(rest);
+        let (host, path) = rest
+            .split_once('/')
+            .with_context(|| format!("no repo path in URL {}", url))?;
+        let forge = match forge.or_else(|| Forge::infer(host)) {
+            Some(forge) => forge,
+            None => bail!("unknown forge for host {}; set forge in config", host),
+        };
+        let (owner, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let valid = match forge {
+            // GitLab allows nested groups
+            Forge::Github => !owner.is_empty() && !owner.contains('/'),
+            Forge::Gitlab => !owner.is_empty(),
+            Forge::Generic => true,
+        };
+        if !valid || name.is_empty() {
+            bail!("couldn't find owner and repo name in URL {}", url);
+        }
+        Ok(Self {
+            forge,
+            host: host.into(),
+            owner: owner.into(),
+            name: name.into(),
+        })
+    }
+
+    /// owner/name, or just the name if there's no owner
+    pub fn slug(&self) -> String {
+        if self.owner.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}/{}", self.owner, self.name)
+        }
+    }
+
+    /// URL of a fork of this repo owned by owner
+    pub fn fork_url(&self, owner: &str) -> Result<String> {
+        match self.forge {
+            Forge::Github | Forge::Gitlab => {
+                Ok(format!("https://{}/{}/{}", self.host, owner, self.name))
+            }
+            Forge::Generic => bail!(
+                "can't derive fork URL for {} on generic forge; use --fork-regex",
+                self.slug()
+            ),
+        }
+    }
+
+    /// Link to a commit in this repo for use in commit messages and PR
+    /// descriptions on target.  Forges link owner/name@commit references
+    /// to repos on the same host; otherwise use a full URL.
+    pub fn commit_ref(&self, commit: &str, target: Option<&Location>) -> Option<String> {
+        let same_host = target
+            .map(|t| t.forge == self.forge && t.host == self.host)
+            .unwrap_or(false);
+        match self.forge {
+            Forge::Github | Forge::Gitlab if same_host => {
+                Some(format!("{}@{}", self.slug(), commit))
+            }
+            Forge::Github => Some(format!(
+                "https://{}/{}/commit/{}",
+                self.host,
+                self.slug(),
+                commit
+            )),
+            Forge::Gitlab => Some(format!(
+                "https://{}/{}/-/commit/{}",
+                self.host,
+                self.slug(),
+                commit
+            )),
+            Forge::Generic => None,
+        }
+    }
+}
+
+impl Repo {
+    /// Parse the repo URL according to the configured or inferred forge
+    pub fn location(&self) -> Result<Location> {
+        Location::parse(&self.url, self.forge)
+    }
+}
+
+impl ForkArgs {
+    /// Whether a fork branch should be tracked
+    pub(super) fn is_set(&self) -> bool {
+        self.branch.is_some()
+    }
+
+    /// Fork branch for a repo branch, if fork options were specified.
+    /// Branches other than the default get their own fork branch.
+    pub(super) fn branch_for(&self, target: &Target) -> Option<String> {
+        let branch = self.branch.as_ref()?;
+        Some(match target.branch_name() {
+            Some(name) => format!("{}-{}", branch, name),
+            None => branch.clone(),
+        })
+    }
+
+    /// URL of the fork of a repo, if fork options were specified
+    pub(super) fn url(&self, repo: &Repo) -> Result<Option<String>> {
+        if let (Some(regex), Some(replacement)) = (&self.regex, &self.replacement) {
+            return Ok(Some(
+                regex.replace(&repo.url, replacement.as_str()).into_owned(),
+            ));
+        }
+        match &self.owner {
+            Some(owner) => Ok(Some(repo.location()?.fork_url(owner)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn location() {
+        let loc =
+            Location::parse("https://github.com/coreos/fedora-coreos-config.git", None).unwrap();
+        assert_eq!(loc.forge, Forge::Github);
+        assert_eq!(loc.slug(), "coreos/fedora-coreos-config");
+        assert_eq!(
+            loc.fork_url("coreosbot-releng").unwrap(),
+            "https://github.com/coreosbot-releng/fedora-coreos-config"
+        );
+        assert_eq!(
+            loc.commit_ref("abc", Some(&loc)).unwrap(),
+            "coreos/fedora-coreos-config@abc"
+        );
+
+        let gitlab = Location::parse("https://gitlab.com/group/sub/project/", None).unwrap();
+        assert_eq!(gitlab.owner, "group/sub");
+        assert_eq!(
+            loc.commit_ref("abc", Some(&gitlab)).unwrap(),
+            "https://github.com/coreos/fedora-coreos-config/commit/abc"
+        );
+        assert_eq!(
+            gitlab.commit_ref("abc", None).unwrap(),
+            "https://gitlab.com/group/sub/project/-/commit/abc"
+        );
+
+        let pagure =
+            Location::parse("https://pagure.io/fedora-infra", Some(Forge::Generic)).unwrap();
+        assert_eq!(pagure.slug(), "fedora-infra");
+        assert!(pagure.fork_url("bot").is_err());
+        assert!(pagure.commit_ref("abc", None).is_none());
+
+        assert!(Location::parse("https://pagure.io/fedora-infra", None).is_err());
+        assert!(Location::parse("https://github.com/a/b/c", None).is_err());
+        assert!(Location::parse("file:///srv/repo", None).is_err());
+    }
+}
@@ -0,0 +1,795 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+use anyhow::Context;
+
+use super::*;
+
+/// Name of the local branch tracking the remote default branch
+pub const DEFAULT_BRANCH: &str = "DEFAULT";
+
+/// Operations on cached Git checkouts.  `name` is the repo name, used for
+/// prefixing any output.
+pub(super) trait GitBackend: Sync {
+    /// Shallow-clone branch, or the remote's default branch, from url into
+    /// path, naming the local branch DEFAULT_BRANCH.  If supported, fetch
+    /// file contents lazily and check out only the sparse paths.
+    fn clone_default(
+        &self,
+        name: &str,
+        url: &str,
+        branch: Option<&str>,
+        path: &Path,
+        sparse: &BTreeSet<String>,
+    ) -> Result<(), GitError>;
+
+    /// Limit the checkout to the specified paths, if supported
+    fn set_sparse(
+        &self,
+        name: &str,
+        path: &Path,
+        sparse: &BTreeSet<String>,
+    ) -> Result<(), GitError>;
+
+    /// Fetch branch from url and check it out as a detached HEAD
+    fn checkout_remote(
+        &self,
+        name: &str,
+        path: &Path,
+        url: &str,
+        branch: &str,
+    ) -> Result<(), GitError>;
+
+    /// Check out DEFAULT_BRANCH and update it from the origin remote
+    fn checkout_default(&self, name: &str, path: &Path) -> Result<(), GitError>;
+
+    /// Inspect a checkout without modifying it
+    fn status(&self, path: &Path) -> Result<RepoStatus, GitError>;
+
+    /// Commit changes on top of HEAD without modifying the checkout or
+    /// any branch, and return the commit as an mbox-format patch.  If
+    /// author isn't specified, use the configured Git identity.
+    fn format_patch(
+        &self,
+        path: &Path,
+        changes: &[FileChange],
+        message: &str,
+        author: Option<&Author>,
+    ) -> Result<String, GitError>;
+
+    /// Write the contents of the directory path, as of rev, into dest
+    /// without modifying the checkout or its index
+    fn export_tree(&self, path: &Path, rev: &str, dest: &Path) -> Result<(), GitError>;
+
+    /// Create a branch at HEAD and switch to it, keeping any changes to
+    /// the working tree
+    fn create_branch(&self, path: &Path, branch: &str) -> Result<(), GitError>;
+
+    /// Commit the working tree contents of the specified paths, including
+    /// deletions, to the current branch
+    fn commit(
+        &self,
+        path: &Path,
+        paths: &[&str],
+        message: &str,
+        author: Option<&Author>,
+    ) -> Result<(), GitError>;
+}
+
+/// New contents of a file in a commit
+#[derive(Debug)]
+pub(super) struct FileChange<'a> {
+    /// Path relative to the repo root
+    pub path: &'a str,
+    /// Contents and executable bit, or None to delete the file
+    pub new: Option<(&'a str, bool)>,
+}
+
+/// Identity for created commits
+#[derive(Debug, Clone)]
+pub(super) struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+impl std::str::FromStr for Author {
+    type Err = String;
+
+    /// Parse "Name <email>"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s
+            .split_once('<')
+            .ok_or_else(|| "expected \"Name <email>\"".to_string())?;
+        let email = rest
+            .strip_suffix('>')
+            .ok_or_else(|| "expected \"Name <email>\"".to_string())?;
+        Ok(Self {
+            name: name.trim().into(),
+            email: email.trim().into(),
+        })
+    }
+}
+
+/// State of a cached checkout
+#[derive(Debug)]
+pub(super) struct RepoStatus {
+    /// Commit ID of HEAD
+    pub head: String,
+    /// Tracked files with uncommitted changes, relative to the repo root
+    pub changed: Vec<String>,
+    /// Untracked files that aren't ignored, relative to the repo root
+    pub untracked: Vec<String>,
+    /// Queried directory relative to the repo root, with a trailing slash
+    /// unless empty
+    pub prefix: String,
+    /// Whether DEFAULT_BRANCH exists
+    pub has_default: bool,
+    /// URL of the origin remote, if any
+    pub origin: Option<String>,
+}
+
+#[derive(Debug)]
+pub(super) enum GitError {
+    /// Remote repo doesn't exist or isn't accessible
+    RepoMissing(String),
+    /// Remote repo exists but the branch doesn't
+    BranchMissing(String, String),
+    /// Couldn't reach the remote
+    Network(anyhow::Error),
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RepoMissing(url) => write!(f, "repo {} not found", url),
+            Self::BranchMissing(url, branch) => {
+                write!(f, "branch {} not found in {}", branch, url)
+            }
+            Self::Network(e) => write!(f, "network failure: {:#}", e),
+            Self::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for GitError {}
+
+impl From<anyhow::Error> for GitError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Other(e)
+    }
+}
+
+pub(super) fn backend() -> Box<dyn GitBackend> {
+    Box::new(CliBackend)
+}
+
+/// Backend that runs the git command
+struct CliBackend;
+
+impl GitBackend for CliBackend {
+    fn clone_default(
+        &self,
+        name: &str,
+        url: &str,
+        branch: Option<&str>,
+        path: &Path,
+        sparse: &BTreeSet<String>,
+    ) -> Result<(), GitError> {
+        run_command(
+            name,
+            Command::new("git")
+                .args(["clone", "--depth=1", "--filter=blob:none", "--no-checkout"])
+                .args(branch.map(|b| ["--branch", b]).into_iter().flatten())
+                .arg(url)
+                .arg(path)
+                .env("GIT_ASKPASS", "/bin/true"),
+        )
+        .map_err(|e| classify(e, url, branch))?;
+        // use consistent name for default branch
+        run_command(
+            name,
+            Command::new("git")
+                .args(["branch", "-m", DEFAULT_BRANCH])
+                .current_dir(path),
+        )?;
+        self.set_sparse(name, path, sparse)?;
+        run_command(name, Command::new("git").arg("checkout").current_dir(path))
+            .map_err(|e| classify(e, url, None))?;
+        Ok(())
+    }
+
+    fn set_sparse(
+        &self,
+        name: &str,
+        path: &Path,
+        sparse: &BTreeSet<String>,
+    ) -> Result<(), GitError> {
+        run_command(
+            name,
+            Command::new("git")
+                .args(["sparse-checkout", "set", "--no-cone"])
+                .args(sparse.iter().map(|p| sparse_pattern(p)))
+                .current_dir(path),
+        )
+        .map_err(|e| classify(e, "origin", None))?;
+        Ok(())
+    }
+
+    fn checkout_remote(
+        &self,
+        name: &str,
+        path: &Path,
+        url: &str,
+        branch: &str,
+    ) -> Result<(), GitError> {
+        run_command(
+            name,
+            Command::new("git")
+                .args(["fetch", "--depth", "1", url, branch])
+                // disable password prompts so we don't block if the repo is
+                // missing
+                .env("GIT_ASKPASS", "/bin/true")
+                .current_dir(path),
+        )
+        .map_err(|e| classify(e, url, Some(branch)))?;
+        run_command(
+            name,
+            Command::new("git")
+                .args(["-c", "advice.detachedHead=false", "checkout", "FETCH_HEAD"])
+                .current_dir(path),
+        )?;
+        Ok(())
+    }
+
+    fn checkout_default(&self, name: &str, path: &Path) -> Result<(), GitError> {
+        run_command(
+            name,
+            Command::new("git")
+                .args(["checkout", DEFAULT_BRANCH])
+                .current_dir(path),
+        )?;
+        run_command(name, Command::new("git").arg("pull").current_dir(path))
+            .map_err(|e| classify(e, "origin", None))?;
+        Ok(())
+    }
+
+    fn status(&self, path: &Path) -> Result<RepoStatus, GitError> {
+        let git = || {
+            let mut cmd = Command::new("git");
+            cmd.current_dir(path);
+            cmd
+        };
+        // callers probe arbitrary directories, so don't print git's
+        // complaint if this one isn't a repo
+        let mut cmd = git();
+        cmd.args(["rev-parse", "HEAD"]);
+        let head = cmd.output().context("running git rev-parse")?;
+        if !head.status.success() {
+            return Err(GitError::Other(
+                CommandError {
+                    desc: describe(&cmd),
+                    stderr: String::from_utf8_lossy(&head.stderr).into_owned(),
+                }
+                .into(),
+            ));
+        }
+        let head = String::from_utf8_lossy(&head.stdout);
+        let prefix = command_output(git().args(["rev-parse", "--show-prefix"]), None)?;
+        let output = command_output(
+            git().args(["status", "--porcelain", "-z", "--untracked-files=all"]),
+            None,
+        )?;
+        // entries are "XY path"; renames and copies are followed by the
+        // original path
+        let mut changed = Vec::new();
+        let mut untracked = Vec::new();
+        let mut entries = output.split_terminator('\0');
+        while let Some(entry) = entries.next() {
+            let (status, path) = entry.split_at(3.min(entry.len()));
+            if status == "?? " {
+                untracked.push(path.to_string());
+                continue;
+            }
+            changed.push(path.to_string());
+            if status.contains(['R', 'C']) {
+                entries.next();
+            }
+        }
+        let has_default = git()
+            .args(["rev-parse", "--verify", "--quiet"])
+            .arg(format!("refs/heads/{}", DEFAULT_BRANCH))
+            .output()
+            .context("running git rev-parse")?
+            .status
+            .success();
+        let origin = git()
+            .args(["config", "--get", "remote.origin.url"])
+            .output()
+            .context("running git config")?;
+        let origin = if origin.status.success() {
+            Some(String::from_utf8_lossy(&origin.stdout).trim().into())
+        } else {
+            None
+        };
+        Ok(RepoStatus {
+            head: head.trim().into(),
+            changed,
+            untracked,
+            prefix: prefix.trim_end_matches('\n').into(),
+            has_default,
+            origin,
+        })
+    }
+
+    fn format_patch(
+        &self,
+        path: &Path,
+        changes: &[FileChange],
+        message: &str,
+        author: Option<&Author>,
+    ) -> Result<String, GitError> {
+        // build the tree in a private index, leaving the checkout alone.
+        // relative to the working directory of the command
+        let index = Path::new(".git/tmpl8-index");
+        let git = || {
+            let mut cmd = git_command(path, author);
+            cmd.env("GIT_INDEX_FILE", index);
+            cmd
+        };
+        let result = (|| {
+            command_output(git().args(["read-tree", "HEAD"]), None)?;
+            let mut index_info = String::new();
+            for change in changes {
+                match change.new {
+                    Some((contents, executable)) => {
+                        let id = command_output(
+                            git().args(["hash-object", "-w", "--stdin"]),
+                            Some(contents),
+                        )?;
+                        let mode = if executable { "100755" } else { "100644" };
+                        index_info.push_str(&format!("{} {}\t{}\n", mode, id.trim(), change.path));
+                    }
+                    // mode 0 removes the path
+                    None => index_info.push_str(&format!(
+                        "0 0000000000000000000000000000000000000000\t{}\n",
+                        change.path
+                    )),
+                }
+            }
+            command_output(
+                git().args(["update-index", "--index-info"]),
+                Some(&index_info),
+            )?;
+            let tree = command_output(git().arg("write-tree"), None)?;
+            let commit = command_output(
+                git().args(["commit-tree", tree.trim(), "-p", "HEAD", "-F", "-"]),
+                Some(message),
+            )?;
+            command_output(
+                git().args(["format-patch", "-1", "--stdout", commit.trim()]),
+                None,
+            )
+        })();
+        // ignore errors; the index may not have been created
+        let _ = fs::remove_file(path.join(index));
+        Ok(result?)
+    }
+
+    fn export_tree(&self, path: &Path, rev: &str, dest: &Path) -> Result<(), GitError> {
+        // read the tree into a private index and check it out from there
+        let index = tempfile::NamedTempFile::new().context("creating temporary index")?;
+        let git = || {
+            let mut cmd = git_command(path, None);
+            cmd.env("GIT_INDEX_FILE", index.path());
+            cmd
+        };
+        let prefix = command_output(git().args(["rev-parse", "--show-prefix"]), None)?;
+        command_output(
+            git()
+                .arg("read-tree")
+                .arg(format!("{}:{}", rev, prefix.trim())),
+            None,
+        )?;
+        // checkout-index wants a trailing slash to treat the prefix as
+        // a directory
+        let mut prefix = dest.as_os_str().to_owned();
+        prefix.push("/");
+        command_output(
+            git().args(["checkout-index", "-a", "--prefix"]).arg(prefix),
+            None,
+        )?;
+        Ok(())
+    }
+
+    fn create_branch(&self, path: &Path, branch: &str) -> Result<(), GitError> {
+        command_output(
+            git_command(path, None).args(["checkout", "-q", "-b", branch]),
+            None,
+        )?;
+        Ok(())
+    }
+
+    fn commit(
+        &self,
+        path: &Path,
+        paths: &[&str],
+        message: &str,
+        author: Option<&Author>,
+    ) -> Result<(), GitError> {
+        command_output(
+            git_command(path, author)
+                .args(["add", "-A", "--"])
+                .args(paths),
+            None,
+        )?;
+        command_output(
+            git_command(path, author)
+                .args(["commit", "-q", "-F", "-", "--"])
+                .args(paths),
+            Some(message),
+        )?;
+        Ok(())
+    }
+}
+
+/// git command running in path, with the specified author and committer
+fn git_command(path: &Path, author: Option<&Author>) -> Command {
+    let mut cmd = Command::new("git");
+    cmd.current_dir(path);
+    if let Some(author) = author {
+        cmd.env("GIT_AUTHOR_NAME", &author.name)
+            .env("GIT_AUTHOR_EMAIL", &author.email)
+            .env("GIT_COMMITTER_NAME", &author.name)
+            .env("GIT_COMMITTER_EMAIL", &author.email);
+    }
+    cmd
+}
+
+/// Sparse checkout pattern matching exactly one path
+fn sparse_pattern(path: &str) -> String {
+    let mut ret = String::from("/");
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\' | '!' | '#') {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+/// Failed command, with its stderr
+#[derive(Debug)]
+struct CommandError {
+    desc: String,
+    stderr: String,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "command failed: '{}'", self.desc)
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Classify a failed git command by its error output
+fn classify(e: anyhow::Error, url: &str, branch: Option<&str>) -> GitError {
+    let stderr = match e.downcast_ref::<CommandError>() {
+        Some(c) => &c.stderr,
+        None => return GitError::Other(e),
+    };
+    if let Some(branch) = branch {
+        if stderr.contains("couldn't find remote ref") || stderr.contains("not found in upstream") {
+            return GitError::BranchMissing(url.into(), branch.into());
+        }
+    }
+    if [
+        "Repository not found",
+        "Authentication failed",
+        "could not read Username",
+        "does not appear to be a git repository",
+    ]
+    .iter()
+    .any(|s| stderr.contains(s))
+    {
+        return GitError::RepoMissing(url.into());
+    }
+    if [
+        "Could not resolve host",
+        "Failed to connect",
+        "Connection timed out",
+        "Connection refused",
+    ]
+    .iter()
+    .any(|s| stderr.contains(s))
+    {
+        return GitError::Network(e);
+    }
+    GitError::Other(e)
+}
+
+/// Run a command, printing its output prefixed with the repo name
+fn run_command(name: &str, cmd: &mut Command) -> anyhow::Result<()> {
+    let desc = describe(cmd);
+    let output = cmd
+        .output()
+        .with_context(|| format!("running '{}'", desc))?;
+    print_output(name, &output);
+    if !output.status.success() {
+        return Err(CommandError {
+            desc,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Run a command, optionally writing input to its stdin, and return its
+/// stdout
+fn command_output(cmd: &mut Command, input: Option<&str>) -> anyhow::Result<String> {
+    let desc = describe(cmd);
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("running '{}'", desc))?;
+    // drop stdin after writing, so the command sees EOF
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(input.unwrap_or("").as_bytes())
+        .with_context(|| format!("writing to '{}'", desc))?;
+    drop(stdin);
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("running '{}'", desc))?;
+    if !output.status.success() {
+        // ignore errors writing to stderr
+        let _ = io::stderr().write_all(&output.stderr);
+        return Err(CommandError {
+            desc,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+        .into());
+    }
+    String::from_utf8(output.stdout).with_context(|| format!("decoding output of '{}'", desc))
+}
+
+fn describe(cmd: &Command) -> String {
+    format!(
+        "{} {}",
+        cmd.get_program().to_string_lossy(),
+        cmd.get_args()
+            .map(|v| v.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+    )
+}
+
+/// Print buffered command output to stderr without interleaving it with
+/// output from other threads
+fn print_output(name: &str, output: &Output) {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut lock = io::stderr().lock();
+    for line in stdout.lines().chain(stderr.lines()) {
+        // ignore errors writing to stderr
+        let _ = writeln!(lock, "{}: {}", name, line);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::process::Stdio;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_AUTHOR_NAME", "Test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "Test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    fn output(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn commit(dir: &Path, contents: &str) {
+        fs::write(dir.join("file"), contents).unwrap();
+        git(dir, &["add", "file"]);
+        git(dir, &["commit", "-m", contents]);
+    }
+
+    fn check_backend() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream");
+        let fork = temp.path().join("fork");
+        let cache = temp.path().join("cache");
+        let url = |p: &Path| format!("file://{}", p.display());
+        let read = || fs::read_to_string(cache.join("file")).unwrap();
+
+        fs::create_dir(&upstream).unwrap();
+        git(&upstream, &["init", "-b", "main"]);
+        fs::write(upstream.join("other"), "other").unwrap();
+        git(&upstream, &["add", "other"]);
+        commit(&upstream, "upstream");
+        git(temp.path(), &["clone", &url(&upstream), "fork"]);
+        git(&fork, &["checkout", "-b", "repo-templates"]);
+        commit(&fork, "fork");
+
+        let git = backend();
+        let paths = |p: &[&str]| p.iter().map(|s| s.to_string()).collect();
+        git.clone_default("test", &url(&upstream), None, &cache, &paths(&["file"]))
+            .unwrap();
+        assert_eq!(read(), "upstream");
+        assert!(!cache.join("other").exists());
+        let status = git.status(&cache).unwrap();
+        assert_eq!(status.head.len(), 40);
+        assert!(status.changed.is_empty());
+        assert!(status.has_default);
+        assert_eq!(status.origin, Some(url(&upstream)));
+        fs::write(cache.join("file"), "modified").unwrap();
+        assert_eq!(git.status(&cache).unwrap().changed, ["file"]);
+        fs::write(cache.join("file"), "upstream").unwrap();
+        fs::create_dir(cache.join("dir")).unwrap();
+        fs::write(cache.join("dir/new"), "new").unwrap();
+        let status = git.status(&cache.join("dir")).unwrap();
+        assert!(status.changed.is_empty());
+        assert_eq!(status.untracked, ["dir/new"]);
+        assert_eq!(status.prefix, "dir/");
+        assert_eq!(git.status(&cache).unwrap().prefix, "");
+        fs::remove_dir_all(cache.join("dir")).unwrap();
+
+        git.checkout_remote("test", &cache, &url(&fork), "repo-templates")
+            .unwrap();
+        assert_eq!(read(), "fork");
+        assert!(matches!(
+            git.checkout_remote("test", &cache, &url(&fork), "nonexistent"),
+            Err(GitError::BranchMissing(..))
+        ));
+        assert!(matches!(
+            git.checkout_remote(
+                "test",
+                &cache,
+                &url(&temp.path().join("nonexistent")),
+                "repo-templates"
+            ),
+            Err(GitError::RepoMissing(_))
+        ));
+
+        commit(&upstream, "upstream 2");
+        git.checkout_default("test", &cache).unwrap();
+        assert_eq!(read(), "upstream 2");
+        assert!(!cache.join("other").exists());
+
+        git.set_sparse("test", &cache, &paths(&["file", "other"]))
+            .unwrap();
+        assert!(cache.join("other").exists());
+
+        let patch = git
+            .format_patch(
+                &cache,
+                &[
+                    FileChange {
+                        path: "file",
+                        new: Some(("patched\n", true)),
+                    },
+                    FileChange {
+                        path: "other",
+                        new: None,
+                    },
+                ],
+                "Subject\n\nBody\n",
+                Some(&"A U Thor <author@example.com>".parse().unwrap()),
+            )
+            .unwrap();
+        assert!(patch.contains("Subject: [PATCH] Subject"), "{}", patch);
+        assert!(patch.contains("From: A U Thor <author@example.com>"));
+        assert!(patch.contains("new mode 100755"));
+        assert!(patch.contains("+patched"));
+        assert!(patch.contains("deleted file mode 100644"));
+        // checkout is untouched
+        assert_eq!(read(), "upstream 2");
+        assert!(git.status(&cache).unwrap().changed.is_empty());
+
+        let base = git.status(&cache).unwrap().head;
+        fs::write(cache.join("file"), "committed").unwrap();
+        fs::remove_file(cache.join("other")).unwrap();
+        git.create_branch(&cache, "test-branch").unwrap();
+        git.commit(
+            &cache,
+            &["file", "other"],
+            "Commit\n",
+            Some(&"A U Thor <author@example.com>".parse().unwrap()),
+        )
+        .unwrap();
+        let status = git.status(&cache).unwrap();
+        assert_ne!(status.head, base);
+        assert!(status.changed.is_empty());
+        assert_eq!(
+            output(&cache, &["rev-parse", "test-branch", "HEAD~1"]),
+            format!("{}\n{}\n", status.head, base)
+        );
+        assert_eq!(output(&cache, &["ls-files"]), "file\n");
+
+        let export = temp.path().join("export");
+        git.export_tree(&cache, "HEAD~1", &export).unwrap();
+        assert_eq!(
+            fs::read_to_string(export.join("file")).unwrap(),
+            "upstream 2"
+        );
+        assert_eq!(fs::read_to_string(export.join("other")).unwrap(), "other");
+        assert!(git.status(&cache).unwrap().changed.is_empty());
+
+        // clone a non-default branch
+        let branch_cache = temp.path().join("branch-cache");
+        self::git(&upstream, &["checkout", "-b", "release"]);
+        commit(&upstream, "release");
+        self::git(&upstream, &["checkout", "main"]);
+        git.clone_default(
+            "test",
+            &url(&upstream),
+            Some("release"),
+            &branch_cache,
+            &paths(&["file"]),
+        )
+        .unwrap();
+        let read_branch = || fs::read_to_string(branch_cache.join("file")).unwrap();
+        assert_eq!(read_branch(), "release");
+        assert!(git.status(&branch_cache).unwrap().has_default);
+        self::git(&upstream, &["checkout", "release"]);
+        commit(&upstream, "release 2");
+        self::git(&upstream, &["checkout", "main"]);
+        git.checkout_default("test", &branch_cache).unwrap();
+        assert_eq!(read_branch(), "release 2");
+        assert!(git
+            .clone_default(
+                "test",
+                &url(&upstream),
+                Some("nonexistent"),
+                &temp.path().join("missing-branch"),
+                &paths(&["file"]),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn cli_backend() {
+        check_backend();
+    }
+}
@@ -12,32 +12,135 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
 use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde::Serialize;
+use serde_json::{Map, Value};
+use tempfile::TempDir;
 
+use super::cache::*;
+use super::forge::*;
+use super::render::{
+    compare, do_render, managed_paths, target_context, template_config_path, warn, RenderedTemplate,
+};
 use super::schema::*;
 use super::*;
 
 #[derive(Serialize, Debug)]
-struct Matrix {
-    repo: Vec<String>,
+#[serde(rename_all = "lowercase")]
+enum Matrix {
+    /// owner/name of each repo
+    Repo(Vec<String>),
+    /// An object with the selected fields for each repo
+    Include(Vec<Map<String, Value>>),
+}
+
+impl Matrix {
+    fn len(&self) -> usize {
+        match self {
+            Self::Repo(repos) => repos.len(),
+            Self::Include(entries) => entries.len(),
+        }
+    }
+}
+
+/// A property of a repo to include in matrix entries
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) enum MatrixField {
+    /// Name of the repo in the config
+    Key,
+    /// Owner of the repo on its forge
+    Owner,
+    /// Name of the repo on its forge
+    Repo,
+    /// Target branch, or null for the default branch
+    Branch,
+    /// Directory of the repo branch in render output
+    Dir,
+    /// owner/name of the fork, from the fork options
+    Fork,
+    /// Branch to push to in the fork
+    ForkBranch,
+    /// Files managed in the repo branch
+    Paths,
+    /// A global, preset, repo, or branch var, with defaults filled in
+    Var(String),
+}
+
+impl FromStr for MatrixField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "key" => Ok(Self::Key),
+            "owner" => Ok(Self::Owner),
+            "repo" => Ok(Self::Repo),
+            "branch" => Ok(Self::Branch),
+            "dir" => Ok(Self::Dir),
+            "fork" => Ok(Self::Fork),
+            "fork-branch" => Ok(Self::ForkBranch),
+            "paths" => Ok(Self::Paths),
+            _ => match s.strip_prefix("vars.") {
+                Some(name) if !name.is_empty() => Ok(Self::Var(name.into())),
+                _ => Err("expected key, owner, repo, branch, dir, fork, fork-branch, paths, or vars.<name>".into()),
+            },
+        }
+    }
 }
 
 pub(super) fn get_matrix(args: GithubMatrixArgs) -> Result<()> {
-    let cfg = Config::parse(&args.config)?;
-
-    const GITHUB_PREFIX: &str = "https://github.com/";
-    let matrix = Matrix {
-        // ignore non-GitHub repos
-        repo: cfg
-            .repos
-            .iter()
-            .map(|(_, r)| &r.url)
-            .filter(|u| u.starts_with(GITHUB_PREFIX))
-            .map(|u| u.replacen(GITHUB_PREFIX, "", 1))
-            .collect(),
+    let cfg = Config::load(&args.config)?;
+    let fork_fields = [MatrixField::Fork, MatrixField::ForkBranch];
+    if fork_fields.iter().any(|f| args.fields.contains(f)) && !args.fork.is_set() {
+        bail!("fork field requires fork options");
+    }
+    let rendered = if args.changed_only || args.fields.contains(&MatrixField::Paths) {
+        do_render(&args.config, &cfg)?
+    } else {
+        BTreeMap::new()
+    };
+    let changed = if args.changed_only {
+        Some(changed_targets(&args, &cfg, &rendered)?)
+    } else {
+        None
+    };
+    let paths = managed_paths(&rendered)?;
+    let defaults = if args.fields.iter().any(|f| matches!(f, MatrixField::Var(_))) {
+        schema_defaults(&args.config, &cfg)?
+    } else {
+        BTreeMap::new()
+    };
+
+    // only include repos on the requested forge
+    let mut targets = Vec::new();
+    for target in cfg.targets()? {
+        let dir = target.dir();
+        if changed.as_ref().map(|c| !c.contains(&dir)).unwrap_or(false) {
+            continue;
+        }
+        match target.repo.location() {
+            Ok(loc) if loc.forge == args.forge => targets.push((target, loc)),
+            Ok(loc) => warn(format!("skipping {}: hosted on {}", dir, loc.host)),
+            Err(e) => warn(format!("skipping {}: {:#}", dir, e)),
+        }
+    }
+    let matrix = if args.fields.is_empty() {
+        // one entry per repo, however many of its branches are included
+        let mut slugs: Vec<String> = targets.iter().map(|(_, loc)| loc.slug()).collect();
+        slugs.dedup();
+        Matrix::Repo(slugs)
+    } else {
+        Matrix::Include(
+            targets
+                .iter()
+                .map(|(target, loc)| entry(&args, &cfg, target, loc, &paths, &defaults))
+                .collect::<Result<_>>()?,
+        )
     };
     if args.pretty {
         serde_json::to_writer_pretty(&mut io::stdout().lock(), &matrix)
@@ -45,8 +148,249 @@ pub(super) fn get_matrix(args: GithubMatrixArgs) -> Result<()> {
     } else {
         print!("matrix=");
         serde_json::to_writer(&mut io::stdout().lock(), &matrix).context("writing to stdout")?;
+        // GitHub rejects empty matrices, so let the workflow skip the job
+        println!();
+        print!("count={}", matrix.len());
     }
     println!();
 
     Ok(())
 }
+
+/// Build a matrix entry with the selected fields
+fn entry(
+    args: &GithubMatrixArgs,
+    cfg: &Config,
+    target: &Target,
+    loc: &Location,
+    paths: &ManagedPaths,
+    defaults: &BTreeMap<String, tera::Context>,
+) -> Result<Map<String, Value>> {
+    let name = target.dir();
+    let mut ret = Map::new();
+    for field in &args.fields {
+        match field {
+            MatrixField::Key => {
+                ret.insert("key".into(), target.repo_name.into());
+            }
+            MatrixField::Owner => {
+                ret.insert("owner".into(), loc.owner.clone().into());
+            }
+            MatrixField::Repo => {
+                ret.insert("repo".into(), loc.name.clone().into());
+            }
+            MatrixField::Branch => {
+                ret.insert("branch".into(), target.branch_name().into());
+            }
+            MatrixField::Dir => {
+                ret.insert("dir".into(), name.clone().into());
+            }
+            MatrixField::Fork => {
+                // fork options were checked by caller
+                let url = args.fork.url(target.repo)?.unwrap();
+                let fork = Location::parse(&url, Some(loc.forge))
+                    .with_context(|| format!("parsing fork URL for {}", name))?;
+                ret.insert("fork".into(), fork.slug().into());
+            }
+            MatrixField::ForkBranch => {
+                ret.insert("fork-branch".into(), args.fork.branch_for(target).into());
+            }
+            MatrixField::Paths => {
+                let paths: Vec<&str> = paths
+                    .get(&name)
+                    .into_iter()
+                    .flatten()
+                    .map(|p| p.as_str())
+                    .collect();
+                ret.insert("paths".into(), paths.into());
+            }
+            MatrixField::Var(var) => {
+                let no_vars = Vars::default();
+                let defaults = defaults.get(&name).cloned().unwrap_or_default();
+                let value = target_context(cfg, target, &no_vars, &no_vars, &defaults)
+                    .with_context(|| format!("getting vars for {}", name))?
+                    .get(var)
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                if let Value::Object(vars) = ret
+                    .entry("vars")
+                    .or_insert_with(|| Value::Object(Map::new()))
+                {
+                    vars.insert(var.clone(), value);
+                }
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Schema defaults of the templates rendered into each repo branch, keyed
+/// by target directory.  Later templates take precedence.
+fn schema_defaults(config_path: &Path, cfg: &Config) -> Result<BTreeMap<String, tera::Context>> {
+    let mut ret: BTreeMap<String, tera::Context> = BTreeMap::new();
+    for template in &cfg.templates {
+        let tmpl_cfg = TemplateConfig::load(&template_config_path(config_path, template)?, cfg)?;
+        let defaults = tmpl_cfg.defaults().to_context()?;
+        for file in &tmpl_cfg.files {
+            for target in cfg.repo_targets(&file.repo)? {
+                ret.entry(target.dir())
+                    .or_default()
+                    .extend(defaults.clone());
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Directories of repo branches whose rendered files differ from the
+/// upstream cache or a previous render
+fn changed_targets(
+    args: &GithubMatrixArgs,
+    cfg: &Config,
+    rendered: &BTreeMap<PathBuf, RenderedTemplate>,
+) -> Result<BTreeSet<String>> {
+    // keep the lock or the unpacked tarball until we're done comparing
+    let _lock;
+    let _temp;
+    let base_dir = match &args.previous {
+        Some(path) if path.is_dir() => path.clone(),
+        Some(path) => {
+            let temp = TempDir::new().context("creating temporary directory")?;
+            unpack(path, temp.path())?;
+            _temp = temp;
+            _temp.path().to_path_buf()
+        }
+        None => {
+            let cache_dir = cache_dir(&args.config, &args.cache)?;
+            let upstream_dir = cache_dir.join(UPSTREAM_DIR);
+            let fork = ForkArgs::default();
+            _lock = lock_cache(&cache_dir, !args.offline)?;
+            let paths = managed_paths(rendered)?;
+            if args.offline {
+                check_offline(cfg, &upstream_dir, args.refresh.max_age, &fork, &paths)?;
+            } else {
+                do_update_cache(
+                    cfg,
+                    &upstream_dir,
+                    args.refresh.update.jobs,
+                    Some(args.refresh.max_age),
+                    &fork,
+                    &paths,
+                )?;
+            }
+            upstream_dir
+        }
+    };
+
+    let mut ret = BTreeSet::new();
+    for cmp in compare(cfg, rendered, &base_dir)? {
+        if cmp.changed() {
+            ret.insert(cmp.target);
+        }
+    }
+    Ok(ret)
+}
+
+/// Unpack a tarball of `tmpl8 render` output, dropping the top-level
+/// output directory
+fn unpack(tarball: &Path, dest: &Path) -> Result<()> {
+    let file = fs::File::open(tarball).with_context(|| format!("opening {}", tarball.display()))?;
+    let mut archive = tar::Archive::new(file);
+    for entry in archive
+        .entries()
+        .with_context(|| format!("reading {}", tarball.display()))?
+    {
+        let mut entry = entry.with_context(|| format!("reading {}", tarball.display()))?;
+        let path = entry
+            .path()
+            .with_context(|| format!("reading {}", tarball.display()))?
+            .into_owned();
+        if path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            bail!("bad path {} in {}", path.display(), tarball.display());
+        }
+        let rel: PathBuf = path
+            .components()
+            .filter(|c| *c != Component::CurDir)
+            .skip(1)
+            .collect();
+        if rel.as_os_str().is_empty() {
+            continue;
+        }
+        let kind = entry.header().entry_type();
+        if !kind.is_file() && !kind.is_dir() {
+            bail!(
+                "unsupported entry type for {} in {}",
+                path.display(),
+                tarball.display()
+            );
+        }
+        let out = dest.join(&rel);
+        if let Some(parent) = out.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+        }
+        entry
+            .unpack(&out)
+            .with_context(|| format!("unpacking {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matrix_field() {
+        let parse = |s: &str| s.parse::<MatrixField>();
+        assert_eq!(parse("key").unwrap(), MatrixField::Key);
+        assert_eq!(parse("fork-branch").unwrap(), MatrixField::ForkBranch);
+        assert_eq!(
+            parse("vars.stream").unwrap(),
+            MatrixField::Var("stream".into())
+        );
+        for bad in ["", "Key", "vars.", "vars", "fork_branch", "url"] {
+            assert!(parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn unpack_paths() {
+        let temp = tempfile::tempdir().unwrap();
+        // build a tarball with arbitrary paths, bypassing the validation
+        // in tar::Builder
+        let tarball = |name: &str, paths: &[&str]| {
+            let path = temp.path().join(name);
+            let mut builder = tar::Builder::new(fs::File::create(&path).unwrap());
+            for p in paths {
+                let mut header = tar::Header::new_old();
+                header.as_old_mut().name[..p.len()].copy_from_slice(p.as_bytes());
+                header.set_size(1);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append(&header, &b"x"[..]).unwrap();
+            }
+            builder.finish().unwrap();
+            path
+        };
+
+        let dest = temp.path().join("good");
+        unpack(
+            &tarball("good.tar", &["output/a/f.txt", "./output/b/.github/g"]),
+            &dest,
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(dest.join("a/f.txt")).unwrap(), "x");
+        assert!(dest.join("b/.github/g").exists());
+
+        for (name, bad) in [("abs.tar", "/etc/x"), ("dotdot.tar", "output/../../x")] {
+            let dest = temp.path().join(name).with_extension("out");
+            let err = unpack(&tarball(name, &[bad]), &dest).unwrap_err();
+            assert!(err.to_string().starts_with("bad path"), "{}", err);
+            assert!(!temp.path().join("x").exists());
+            assert!(!dest.join("etc/x").exists());
+        }
+    }
+}
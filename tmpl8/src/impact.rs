@@ -0,0 +1,162 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tempfile::TempDir;
+
+use super::git::*;
+use super::render::{do_render, RenderedTemplate};
+use super::schema::*;
+use super::*;
+
+pub(super) fn impact(args: ImpactArgs) -> Result<()> {
+    let git = backend();
+    let config_dir = Config::dir(&args.config);
+    let config_name = args
+        .config
+        .file_name()
+        .with_context(|| format!("path {} has no filename", args.config.display()))?;
+
+    // render each revision from a copy of its tree; the working tree
+    // stands in for a missing --to
+    // temporary directories are removed when dropped
+    let mut trees = Vec::new();
+    let mut configs = Vec::new();
+    for rev in [Some(&args.from), args.to.as_ref()] {
+        match rev {
+            Some(rev) => {
+                let dir = TempDir::new().context("creating temporary directory")?;
+                git.export_tree(config_dir, rev, dir.path())
+                    .with_context(|| format!("reading revision {}", rev))?;
+                configs.push(dir.path().join(config_name));
+                trees.push(dir);
+            }
+            None => configs.push(args.config.clone()),
+        }
+    }
+    let old = render_at(&configs[0]).with_context(|| format!("rendering {}", args.from))?;
+    let new = render_at(&configs[1])
+        .with_context(|| format!("rendering {}", args.to.as_deref().unwrap_or("working tree")))?;
+
+    let changes = changed_files(&old, &new)?;
+    let count: usize = changes.values().map(|files| files.len()).sum();
+    for (dir, files) in &changes {
+        println!("{}", dir);
+        for (path, status) in files {
+            println!("  {:8}  {}", status, path.display());
+        }
+    }
+    if !changes.is_empty() {
+        println!();
+    }
+    println!("{} files changed in {} repos", count, changes.len());
+    Ok(())
+}
+
+/// Return the managed files that differ between two renders, as repo dir
+/// -> path -> status.
+fn changed_files<'a>(
+    old: &'a BTreeMap<PathBuf, RenderedTemplate>,
+    new: &'a BTreeMap<PathBuf, RenderedTemplate>,
+) -> Result<BTreeMap<&'a str, BTreeMap<&'a Path, &'static str>>> {
+    let mut changes: BTreeMap<&str, BTreeMap<&Path, &str>> = BTreeMap::new();
+    let paths: BTreeSet<&PathBuf> = old.keys().chain(new.keys()).collect();
+    for path in paths {
+        let (status, result) = match (old.get(path), new.get(path)) {
+            (None, Some(n)) => ("added", n),
+            (Some(o), None) => ("removed", o),
+            (Some(o), Some(n)) if o.contents != n.contents || o.executable != n.executable => {
+                ("modified", n)
+            }
+            _ => continue,
+        };
+        // the manifest changes whenever anything else does
+        if result.template.is_none() {
+            continue;
+        }
+        changes
+            .entry(&result.target)
+            .or_default()
+            .insert(result.relative(path)?, status);
+    }
+    Ok(changes)
+}
+
+fn render_at(config_path: &Path) -> Result<BTreeMap<PathBuf, RenderedTemplate>> {
+    let cfg = Config::load(config_path)?;
+    do_render(config_path, &cfg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn attribution() {
+        let file = |target: &str, contents: &str, executable: bool, template: Option<&str>| {
+            RenderedTemplate {
+                contents: contents.into(),
+                executable,
+                repo: target.split('@').next().unwrap().into(),
+                branch: target.split_once('@').map(|(_, b)| b.into()),
+                target: target.into(),
+                template: template.map(String::from),
+            }
+        };
+        let render = |files: Vec<(&str, RenderedTemplate)>| -> BTreeMap<PathBuf, RenderedTemplate> {
+            files
+                .into_iter()
+                .map(|(path, f)| (Path::new(&f.target).join(path), f))
+                .collect()
+        };
+        let old = render(vec![
+            ("same.md", file("a", "x", false, Some("same.md"))),
+            ("edited.md", file("a", "x", false, Some("edited.md"))),
+            ("script.sh", file("a", "x", false, Some("script.sh"))),
+            ("dropped.md", file("b", "x", false, Some("dropped.md"))),
+            ("manifest.json", file("b", "x", false, None)),
+            ("t.md", file("c@old", "x", false, Some("t.md"))),
+        ]);
+        let new = render(vec![
+            ("same.md", file("a", "x", false, Some("same.md"))),
+            ("edited.md", file("a", "y", false, Some("edited.md"))),
+            ("script.sh", file("a", "x", true, Some("script.sh"))),
+            ("manifest.json", file("b", "y", false, None)),
+            ("t.md", file("c@old", "x", false, Some("t.md"))),
+            ("t.md", file("c@new", "x", false, Some("t.md"))),
+        ]);
+
+        let changes = changed_files(&old, &new).unwrap();
+        let changes: Vec<(&str, &str, &str)> = changes
+            .iter()
+            .flat_map(|(dir, files)| {
+                files
+                    .iter()
+                    .map(move |(path, status)| (*dir, path.to_str().unwrap(), *status))
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("a", "edited.md", "modified"),
+                ("a", "script.sh", "modified"),
+                ("b", "dropped.md", "removed"),
+                ("c@new", "t.md", "added"),
+            ]
+        );
+    }
+}
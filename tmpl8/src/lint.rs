@@ -0,0 +1,410 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{bail, Context, Result};
+use tera::ast::{Expr, ExprVal, FunctionCall, Node};
+use tera::Tera;
+
+use super::render::{load_templates, template_config_path};
+use super::schema::*;
+use super::*;
+
+pub(super) fn lint(args: LintArgs) -> Result<()> {
+    let cfg = Config::load(&args.config)?;
+    let tera = load_templates(&args.config, &cfg)?;
+
+    let mut problems = Vec::new();
+    // all variables read by any template or templated value
+    let mut all_refs = value_refs(&cfg.vars)?;
+    // repo name -> variables read by templated values in its config
+    let mut config_refs: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (repo_name, repo) in &cfg.repos {
+        let refs = config_refs.entry(repo_name.clone()).or_default();
+        refs.extend(all_refs.iter().cloned());
+        refs.extend(value_refs(&repo.vars)?);
+        for branch in &repo.branches {
+            refs.extend(value_refs(&branch.vars)?);
+        }
+        for preset in repo.presets.iter().filter_map(|p| cfg.presets.get(p)) {
+            refs.extend(value_refs(&preset.vars)?);
+            refs.extend(value_refs(&preset.defaults)?);
+        }
+        all_refs.extend(refs.iter().cloned());
+    }
+    // repo name -> variables read by templates rendered into that repo, or
+    // by templated values in its config
+    let mut repo_refs = config_refs.clone();
+    for template in &cfg.templates {
+        let tmpl_cfg_path = template_config_path(&args.config, template)?;
+        let tmpl_cfg = TemplateConfig::load(&tmpl_cfg_path, &cfg)?;
+        let refs = References::new(
+            &tera
+                .templates
+                .get(template)
+                .with_context(|| format!("no such template: {}", template))?
+                .ast,
+        );
+        // variables read by the template, its templated values, or
+        // templated values in the config of its repos
+        let mut tmpl_refs: BTreeSet<String> = refs.all().cloned().collect();
+        tmpl_refs.extend(value_refs(&tmpl_cfg.vars)?);
+        let mut used = tmpl_refs.clone();
+        for file in &tmpl_cfg.files {
+            used.extend(value_refs(&file.vars)?);
+            used.extend(config_refs.get(&file.repo).into_iter().flatten().cloned());
+        }
+
+        // variables read by the template but never defined for any file
+        let defaults = tmpl_cfg.defaults();
+        for name in &refs.required {
+            let defined = tmpl_cfg.files.iter().any(|file| {
+                [&defaults, &cfg.vars, &tmpl_cfg.vars, &file.vars]
+                    .iter()
+                    .any(|vars| vars.contains(name))
+                    || file
+                        .repo(&cfg)
+                        .map(|repo| {
+                            repo.vars.contains(name)
+                                || repo.branches.iter().any(|b| b.vars.contains(name))
+                                || repo
+                                    .presets
+                                    .iter()
+                                    .filter_map(|p| cfg.presets.get(p))
+                                    .any(|p| p.vars.contains(name) || p.defaults.contains(name))
+                        })
+                        .unwrap_or(false)
+            });
+            if !defined {
+                problems.push(format!("{}: undefined variable: {}", template, name));
+            }
+        }
+
+        // variables defined in the template YAML but never read
+        let tmpl_cfg_path = tmpl_cfg_path.display();
+        for name in tmpl_cfg.vars.names() {
+            if !used.contains(name) {
+                problems.push(format!("{}: unused variable: {}", tmpl_cfg_path, name));
+            }
+        }
+        for file in &tmpl_cfg.files {
+            for name in file.vars.names() {
+                if !used.contains(name) {
+                    problems.push(format!(
+                        "{}: {}: unused variable: {}",
+                        tmpl_cfg_path,
+                        file.path().display(),
+                        name
+                    ));
+                }
+            }
+            repo_refs
+                .entry(file.repo.clone())
+                .or_default()
+                .extend(tmpl_refs.iter().cloned().chain(value_refs(&file.vars)?));
+        }
+        all_refs.extend(used);
+    }
+
+    // variables defined in the config but never read
+    for name in cfg.vars.names() {
+        if !all_refs.contains(name) {
+            let path = cfg.var_source(name).unwrap_or(&args.config);
+            problems.push(format!("{}: unused variable: {}", path.display(), name));
+        }
+    }
+    for (preset_name, preset) in &cfg.presets {
+        let used = |name: &str| {
+            cfg.repos.iter().any(|(repo_name, repo)| {
+                repo.presets.contains(preset_name)
+                    && repo_refs
+                        .get(repo_name)
+                        .map(|r| r.contains(name))
+                        .unwrap_or(false)
+            })
+        };
+        for name in preset.vars.names().chain(preset.defaults.names()) {
+            if !used(name) {
+                problems.push(format!(
+                    "{}: preset {}: unused variable: {}",
+                    args.config.display(),
+                    preset_name,
+                    name
+                ));
+            }
+        }
+    }
+    for (repo_name, repo) in &cfg.repos {
+        let config_path = repo.source.as_deref().unwrap_or(&args.config).display();
+        let refs = repo_refs.get(repo_name);
+        for name in repo.vars.names() {
+            if !refs.map(|r| r.contains(name)).unwrap_or(false) {
+                problems.push(format!(
+                    "{}: repo {}: unused variable: {}",
+                    config_path, repo_name, name
+                ));
+            }
+        }
+        for branch in &repo.branches {
+            for name in branch.vars.names() {
+                if !refs.map(|r| r.contains(name)).unwrap_or(false) {
+                    problems.push(format!(
+                        "{}: repo {} branch {}: unused variable: {}",
+                        config_path, repo_name, branch.name, name
+                    ));
+                }
+            }
+        }
+    }
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        bail!("found {} problems", problems.len());
+    }
+    Ok(())
+}
+
+/// Variables read by the templated values in vars
+fn value_refs(vars: &Vars) -> Result<BTreeSet<String>> {
+    let mut ret = BTreeSet::new();
+    for (name, template) in vars.templated() {
+        let mut tera = Tera::default();
+        tera.add_raw_template(name, template)
+            .with_context(|| format!("parsing variable {}", name))?;
+        ret.extend(References::new(&tera.templates[name].ast).all().cloned());
+    }
+    Ok(ret)
+}
+
+/// Context variables referenced by a template
+#[derive(Default, Debug)]
+pub(super) struct References {
+    /// Variables that must be defined
+    required: BTreeSet<String>,
+    /// Variables guarded by `is defined` or a `default` filter
+    optional: BTreeSet<String>,
+    /// Variables assigned or bound within the template
+    local: BTreeSet<String>,
+}
+
+impl References {
+    pub(super) fn new(ast: &[Node]) -> Self {
+        let mut refs = Self::default();
+        // Tera builtins
+        refs.local.insert("loop".into());
+        refs.local.insert("__tera_context".into());
+        refs.walk_nodes(ast);
+
+        let local = &refs.local;
+        refs.required.retain(|name| !local.contains(name));
+        refs.optional.retain(|name| !local.contains(name));
+        refs.required.retain(|name| !refs.optional.contains(name));
+        refs
+    }
+
+    pub(super) fn all(&self) -> impl Iterator<Item = &String> {
+        self.required.iter().chain(self.optional.iter())
+    }
+
+    fn walk_nodes(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.walk_node(node);
+        }
+    }
+
+    fn walk_node(&mut self, node: &Node) {
+        match node {
+            Node::VariableBlock(_, expr) => self.walk_expr(expr),
+            Node::MacroDefinition(_, def, _) => {
+                self.local.extend(def.args.keys().cloned());
+                for expr in def.args.values().flatten() {
+                    self.walk_expr(expr);
+                }
+                self.walk_nodes(&def.body);
+            }
+            Node::Set(_, set) => {
+                self.local.insert(set.key.clone());
+                self.walk_expr(&set.value);
+            }
+            Node::FilterSection(_, section, _) => {
+                self.walk_call(&section.filter);
+                self.walk_nodes(&section.body);
+            }
+            Node::Block(_, block, _) => self.walk_nodes(&block.body),
+            Node::Forloop(_, forloop, _) => {
+                self.local.extend(forloop.key.iter().cloned());
+                self.local.insert(forloop.value.clone());
+                self.walk_expr(&forloop.container);
+                self.walk_nodes(&forloop.body);
+                if let Some(body) = &forloop.empty_body {
+                    self.walk_nodes(body);
+                }
+            }
+            Node::If(cond, _) => {
+                for (_, expr, body) in &cond.conditions {
+                    self.walk_expr(expr);
+                    self.walk_nodes(body);
+                }
+                if let Some((_, body)) = &cond.otherwise {
+                    self.walk_nodes(body);
+                }
+            }
+            Node::Super
+            | Node::Text(_)
+            | Node::Extends(..)
+            | Node::Include(..)
+            | Node::ImportMacro(..)
+            | Node::Raw(..)
+            | Node::Break(_)
+            | Node::Continue(_)
+            | Node::Comment(..) => (),
+        }
+    }
+
+    fn walk_expr(&mut self, expr: &Expr) {
+        if expr.has_default_filter() {
+            if let ExprVal::Ident(ident) = &expr.val {
+                self.optional.insert(root(ident));
+            }
+        }
+        self.walk_val(&expr.val);
+        for filter in &expr.filters {
+            self.walk_call(filter);
+        }
+    }
+
+    fn walk_val(&mut self, val: &ExprVal) {
+        match val {
+            ExprVal::Ident(ident) => {
+                self.required.insert(root(ident));
+            }
+            ExprVal::Math(math) => {
+                self.walk_expr(&math.lhs);
+                self.walk_expr(&math.rhs);
+            }
+            ExprVal::Logic(logic) => {
+                self.walk_expr(&logic.lhs);
+                self.walk_expr(&logic.rhs);
+            }
+            ExprVal::Test(test) => {
+                if test.name == "defined" || test.name == "undefined" {
+                    self.optional.insert(root(&test.ident));
+                } else {
+                    self.required.insert(root(&test.ident));
+                }
+                for arg in &test.args {
+                    self.walk_expr(arg);
+                }
+            }
+            ExprVal::MacroCall(call) => {
+                for arg in call.args.values() {
+                    self.walk_expr(arg);
+                }
+            }
+            ExprVal::FunctionCall(call) => self.walk_call(call),
+            ExprVal::Array(items) => {
+                for item in items {
+                    self.walk_expr(item);
+                }
+            }
+            ExprVal::StringConcat(concat) => {
+                for val in &concat.values {
+                    self.walk_val(val);
+                }
+            }
+            ExprVal::In(test) => {
+                self.walk_expr(&test.lhs);
+                self.walk_expr(&test.rhs);
+            }
+            ExprVal::String(_) | ExprVal::Int(_) | ExprVal::Float(_) | ExprVal::Bool(_) => (),
+        }
+    }
+
+    fn walk_call(&mut self, call: &FunctionCall) {
+        for arg in call.args.values() {
+            self.walk_expr(arg);
+        }
+    }
+}
+
+/// Get the top-level variable name from an identifier like `a.b[c]`
+fn root(ident: &str) -> String {
+    ident.split(['.', '[']).next().unwrap_or(ident).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn references() {
+        let mut tera = Tera::default();
+        tera.add_raw_template(
+            "t",
+            r#"{{ a.b }}
+{% if c is defined and d[0] %}{% endif %}
+{% for x in e | default(value=[]) %}{{ x.y }}{{ loop.index }}{% endfor %}
+{% set f = g ~ "s" %}{{ f }}
+{{ h | replace(from=i, to="") }}
+{% if j and not k %}{{ l or m }}{% elif n > 1 %}{% endif %}"#,
+        )
+        .unwrap();
+        let refs = References::new(&tera.templates["t"].ast);
+        assert_eq!(
+            refs.required.iter().collect::<Vec<_>>(),
+            ["a", "d", "g", "h", "i", "j", "k", "l", "m", "n"]
+        );
+        assert_eq!(refs.optional.iter().collect::<Vec<_>>(), ["c", "e"]);
+    }
+
+    #[test]
+    fn misspelled_condition() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = temp.path().join("config.yaml");
+        fs::write(
+            &config,
+            "templates: [t.md]\nrepos:\n  a: {url: https://example.com/a, vars: {library_crate: true}}\n",
+        )
+        .unwrap();
+        fs::write(
+            temp.path().join("t.yaml"),
+            "files:\n  - repo: a\n    path: t.md\n",
+        )
+        .unwrap();
+        let lint = |template: &str| {
+            fs::write(temp.path().join("t.md"), template).unwrap();
+            lint(LintArgs {
+                config: config.clone(),
+            })
+        };
+        lint("{% if library_crate %}x{% endif %}").unwrap();
+        assert_eq!(
+            lint("{% if libary_crate %}x{% endif %}")
+                .unwrap_err()
+                .to_string(),
+            "found 2 problems"
+        );
+        // flags without a value for every file need a schema default
+        fs::write(
+            temp.path().join("t.yaml"),
+            "schema:\n  flag: {type: bool, default: false}\nfiles:\n  - repo: a\n    path: t.md\n",
+        )
+        .unwrap();
+        lint("{% if library_crate and flag %}x{% endif %}").unwrap();
+    }
+}
@@ -12,15 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::process::ExitCode;
 
 use anyhow::Result;
 use clap::builder::ArgPredicate;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use regex::Regex;
 
+mod apply;
 mod cache;
+mod explain;
+mod forge;
+mod git;
 mod github;
+mod impact;
+mod lint;
+mod manifest;
+mod patch;
 mod render;
 mod schema;
 
@@ -34,10 +44,28 @@ enum Cmd {
     Render(RenderArgs),
     /// Print diff from current repo contents
     Diff(DiffArgs),
+    /// Check whether repo contents match rendered templates
+    ///
+    /// Exits 0 if all repos are in sync, 1 if any repo differs from the
+    /// rendered templates, and 2 on error.
+    Check(CheckArgs),
+    /// Write rendered files for one repo into a local checkout
+    Apply(ApplyArgs),
+    /// Write an mbox patch for each repo that has changes
+    ExportPatches(ExportPatchesArgs),
     /// Update cache for diff command (usually unnecessary)
     UpdateCache(UpdateCacheArgs),
+    /// Inspect or clean up the cache
+    #[command(subcommand)]
+    Cache(CacheCmd),
     /// Render GitHub Actions job matrix
     GithubMatrix(GithubMatrixArgs),
+    /// List rendered files affected by changes between two revisions
+    Impact(ImpactArgs),
+    /// Show where each context variable for a template comes from
+    ExplainVars(ExplainVarsArgs),
+    /// Check templates for undefined and unused variables
+    Lint(LintArgs),
 }
 
 #[derive(Debug, Parser)]
@@ -50,6 +78,9 @@ struct RenderArgs {
     /// Render only one repository
     #[arg(short = 'r', long, value_name = "repo-name")]
     repo: Option<String>,
+    /// Branch of the repository to render, if it has several
+    #[arg(long, value_name = "branch", requires = "repo")]
+    target_branch: Option<String>,
 }
 
 #[derive(Debug, Parser)]
@@ -58,10 +89,101 @@ struct DiffArgs {
     #[arg(short = 'c', long, value_name = "file", default_value = "config.yaml")]
     config: PathBuf,
     #[command(flatten)]
+    cache: CacheArgs,
+    #[command(flatten)]
+    refresh: RefreshArgs,
+    #[command(flatten)]
     fork: ForkArgs,
+    /// Compare against the cache as-is, without network access
+    #[arg(long)]
+    offline: bool,
     /// Disable color output
     #[arg(short = 'n', long)]
     no_color: bool,
+    /// Output format
+    #[arg(short = 'f', long, value_name = "format", default_value = "human")]
+    format: DiffFormat,
+    /// Repo contents to compare against
+    #[arg(long, value_name = "base", default_value = "fork")]
+    base: DiffBase,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum DiffBase {
+    /// Fork branch if specified and present, otherwise upstream
+    Fork,
+    /// Upstream default branch
+    Upstream,
+    /// Both fork branch and upstream
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum DiffFormat {
+    /// Colored unified diff
+    Human,
+    /// JSON array of changed files
+    Json,
+    /// One JSON object per changed file
+    Ndjson,
+}
+
+#[derive(Debug, Parser)]
+struct CheckArgs {
+    /// Config file
+    #[arg(short = 'c', long, value_name = "file", default_value = "config.yaml")]
+    config: PathBuf,
+    #[command(flatten)]
+    cache: CacheArgs,
+    #[command(flatten)]
+    refresh: RefreshArgs,
+    #[command(flatten)]
+    fork: ForkArgs,
+    /// Compare against the cache as-is, without network access
+    #[arg(long)]
+    offline: bool,
+}
+
+#[derive(Debug, Parser)]
+struct ApplyArgs {
+    /// Config file
+    #[arg(short = 'c', long, value_name = "file", default_value = "config.yaml")]
+    config: PathBuf,
+    /// Repository name
+    #[arg(short = 'r', long, value_name = "repo-name")]
+    repo: String,
+    /// Path to a checkout of the repository
+    #[arg(short = 'i', long, value_name = "path")]
+    into: PathBuf,
+    /// Branch of the repository to render, if it has several
+    #[arg(long, value_name = "branch")]
+    target_branch: Option<String>,
+    /// Overwrite files with uncommitted changes
+    #[arg(short = 'f', long)]
+    force: bool,
+    /// Commit the changes to a new branch
+    #[arg(short = 'b', long, value_name = "name")]
+    branch: Option<String>,
+    /// Commit author, as "Name <email>" [default: Git identity]
+    #[arg(long, value_name = "author", requires = "branch")]
+    author: Option<git::Author>,
+}
+
+#[derive(Debug, Parser)]
+struct ExportPatchesArgs {
+    /// Config file
+    #[arg(short = 'c', long, value_name = "file", default_value = "config.yaml")]
+    config: PathBuf,
+    #[command(flatten)]
+    cache: CacheArgs,
+    #[command(flatten)]
+    refresh: RefreshArgs,
+    /// Output directory
+    #[arg(short = 'o', long, value_name = "dir")]
+    out: PathBuf,
+    /// Commit author, as "Name <email>" [default: Git identity]
+    #[arg(long, value_name = "author")]
+    author: Option<git::Author>,
 }
 
 #[derive(Debug, Parser)]
@@ -70,13 +192,72 @@ struct UpdateCacheArgs {
     #[arg(short = 'c', long, value_name = "file", default_value = "config.yaml")]
     config: PathBuf,
     #[command(flatten)]
+    cache: CacheArgs,
+    #[command(flatten)]
+    update: UpdateArgs,
+    #[command(flatten)]
+    fork: ForkArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum CacheCmd {
+    /// Show what each cached repo is tracking
+    Status(CacheStatusArgs),
+    /// Remove clones of unconfigured repos and repair broken clones
+    Gc(CacheGcArgs),
+}
+
+#[derive(Debug, Parser)]
+struct CacheStatusArgs {
+    /// Config file
+    #[arg(short = 'c', long, value_name = "file", default_value = "config.yaml")]
+    config: PathBuf,
+    #[command(flatten)]
+    cache: CacheArgs,
+}
+
+#[derive(Debug, Parser)]
+struct CacheGcArgs {
+    /// Config file
+    #[arg(short = 'c', long, value_name = "file", default_value = "config.yaml")]
+    config: PathBuf,
+    #[command(flatten)]
+    cache: CacheArgs,
+    #[command(flatten)]
     fork: ForkArgs,
 }
 
 #[derive(Debug, Parser)]
+struct CacheArgs {
+    /// Cache directory [default: .cache next to config file]
+    #[arg(long, value_name = "dir", env = "TMPL8_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+struct UpdateArgs {
+    /// Number of repos to update in parallel
+    #[arg(short = 'j', long, value_name = "count", default_value = "8")]
+    jobs: NonZeroUsize,
+}
+
+#[derive(Debug, Parser)]
+struct RefreshArgs {
+    #[command(flatten)]
+    update: UpdateArgs,
+    /// Maximum age of cached repos before updating them, in seconds
+    #[arg(long, value_name = "seconds", default_value = "3600")]
+    max_age: u32,
+}
+
+#[derive(Debug, Default, Parser)]
 struct ForkArgs {
+    /// Owner of forks on the repo's forge
+    #[arg(long = "fork-owner", value_name = "owner", group = "fork_target")]
+    #[arg(requires = "branch", conflicts_with = "regex")]
+    owner: Option<String>,
     /// Regex for the upstream part of repo URL
-    #[arg(long = "fork-regex", value_name = "regex")]
+    #[arg(long = "fork-regex", value_name = "regex", group = "fork_target")]
     #[arg(requires_ifs = [
         (ArgPredicate::IsPresent, "replacement"),
         (ArgPredicate::IsPresent, "branch")
@@ -90,11 +271,7 @@ struct ForkArgs {
     ])]
     replacement: Option<String>,
     /// Fork branch
-    #[arg(long = "fork-branch", value_name = "branch")]
-    #[arg(requires_ifs = [
-        (ArgPredicate::IsPresent, "regex"),
-        (ArgPredicate::IsPresent, "replacement")
-    ])]
+    #[arg(long = "fork-branch", value_name = "branch", requires = "fork_target")]
     branch: Option<String>,
 }
 
@@ -106,15 +283,83 @@ struct GithubMatrixArgs {
     /// Print human-readable JSON
     #[arg(short = 'p', long)]
     pretty: bool,
+    /// Only include repos whose rendered files differ from upstream
+    #[arg(long)]
+    changed_only: bool,
+    /// Compare against a previous render directory or tarball instead of
+    /// the cache
+    #[arg(long, value_name = "path", requires = "changed_only")]
+    previous: Option<PathBuf>,
+    #[command(flatten)]
+    cache: CacheArgs,
+    #[command(flatten)]
+    refresh: RefreshArgs,
+    /// Compare against the cache as-is, without network access
+    #[arg(long, requires = "changed_only", conflicts_with = "previous")]
+    offline: bool,
+    /// Emit include-style entries with these fields: key, owner, repo,
+    /// branch, dir, fork, fork-branch, paths, vars.<name>
+    #[arg(long, value_name = "fields", value_delimiter = ',')]
+    fields: Vec<github::MatrixField>,
+    #[command(flatten)]
+    fork: ForkArgs,
+    /// Only include repos hosted on this forge
+    #[arg(long, value_name = "forge", default_value = "github")]
+    forge: forge::Forge,
+}
+
+#[derive(Debug, Parser)]
+struct ExplainVarsArgs {
+    /// Config file
+    #[arg(short = 'c', long, value_name = "file", default_value = "config.yaml")]
+    config: PathBuf,
+    /// Repository name
+    #[arg(short = 'r', long, value_name = "repo-name")]
+    repo: String,
+    /// Branch of the repository, if it has several
+    #[arg(long, value_name = "branch")]
+    target_branch: Option<String>,
+    /// Template path, as listed in config file
+    #[arg(short = 't', long, value_name = "template")]
+    template: String,
+}
+
+#[derive(Debug, Parser)]
+struct ImpactArgs {
+    /// Config file
+    #[arg(short = 'c', long, value_name = "file", default_value = "config.yaml")]
+    config: PathBuf,
+    /// Old revision of this repo
+    #[arg(long, value_name = "rev")]
+    from: String,
+    /// New revision of this repo [default: working tree]
+    #[arg(long, value_name = "rev")]
+    to: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+struct LintArgs {
+    /// Config file
+    #[arg(short = 'c', long, value_name = "file", default_value = "config.yaml")]
+    config: PathBuf,
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     match Cmd::parse() {
-        Cmd::Render(c) => render::render(c),
-        Cmd::Diff(c) => render::diff(c),
-        Cmd::UpdateCache(c) => cache::update_cache(c),
-        Cmd::GithubMatrix(c) => github::get_matrix(c),
+        Cmd::Render(c) => render::render(c)?,
+        Cmd::Diff(c) => render::diff(c)?,
+        Cmd::Check(c) => return Ok(render::check(c)),
+        Cmd::Apply(c) => apply::apply(c)?,
+        Cmd::ExportPatches(c) => patch::export_patches(c)?,
+        Cmd::UpdateCache(c) => cache::update_cache(c)?,
+        Cmd::Cache(CacheCmd::Status(c)) => cache::status(c)?,
+        Cmd::Cache(CacheCmd::Gc(c)) => cache::gc(c)?,
+        Cmd::GithubMatrix(c) => github::get_matrix(c)?,
+        Cmd::Impact(c) => impact::impact(c)?,
+        Cmd::ExplainVars(c) => explain::explain_vars(c)?,
+        Cmd::Lint(c) => lint::lint(c)?,
     }
+    Ok(ExitCode::SUCCESS)
 }
 
 #[cfg(test)]
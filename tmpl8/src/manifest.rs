@@ -0,0 +1,65 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Path of the manifest within each downstream repo
+pub const MANIFEST_PATH: &str = ".github/repo-templates.json";
+
+/// List of files in a downstream repo that are managed by us
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub template: String,
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// Read manifest from a repo checkout, returning None if missing
+    pub fn load(repo_dir: &Path) -> Result<Option<Self>> {
+        let path = repo_dir.join(MANIFEST_PATH);
+        let data = match fs::read(&path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        Ok(Some(
+            serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))?,
+        ))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        let mut ret = serde_json::to_string_pretty(self).context("serializing manifest")?;
+        ret.push('\n');
+        Ok(ret)
+    }
+}
+
+/// Hex SHA-256 of file contents
+pub fn hash(contents: &str) -> String {
+    format!("{:x}", Sha256::digest(contents.as_bytes()))
+}
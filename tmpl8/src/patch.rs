@@ -0,0 +1,142 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use super::cache::*;
+use super::forge::*;
+use super::git::*;
+use super::render::{compare, do_render, managed_paths, Comparison};
+use super::schema::*;
+use super::*;
+
+pub(super) fn export_patches(args: ExportPatchesArgs) -> Result<()> {
+    let cfg = Config::load(&args.config)?;
+    let rendered = do_render(&args.config, &cfg)?;
+
+    // patches apply to the upstream default branch
+    let cache_dir = cache_dir(&args.config, &args.cache)?;
+    let upstream_dir = cache_dir.join(UPSTREAM_DIR);
+    let _lock = lock_cache(&cache_dir, true)?;
+    do_update_cache(
+        &cfg,
+        &upstream_dir,
+        args.refresh.update.jobs,
+        Some(args.refresh.max_age),
+        &ForkArgs::default(),
+        &managed_paths(&rendered)?,
+    )?;
+
+    let git = backend();
+    let source = source_commit(git.as_ref(), &args.config);
+
+    // one patch per repo branch
+    let mut by_target: BTreeMap<String, Vec<Comparison>> = BTreeMap::new();
+    for cmp in compare(&cfg, &rendered, &upstream_dir)? {
+        if cmp.changed() {
+            by_target.entry(cmp.target.clone()).or_default().push(cmp);
+        }
+    }
+    fs::create_dir_all(&args.out).with_context(|| format!("creating {}", args.out.display()))?;
+    for (name, cmps) in &by_target {
+        let changes = cmps
+            .iter()
+            .map(|cmp| {
+                Ok(FileChange {
+                    path: cmp
+                        .relative()?
+                        .to_str()
+                        .with_context(|| format!("bad path {}", cmp.path.display()))?,
+                    new: cmp.new.map(|n| (n.contents.as_str(), n.executable)),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let templates: BTreeSet<&str> = cmps.iter().filter_map(|c| c.template.as_deref()).collect();
+        let target = cfg.repos.get(cmps[0].repo).and_then(|r| r.location().ok());
+        let message = commit_message(source.as_ref(), target.as_ref(), &templates);
+        let patch = git
+            .format_patch(
+                &upstream_dir.join(name),
+                &changes,
+                &message,
+                args.author.as_ref(),
+            )
+            .with_context(|| format!("creating patch for {}", name))?;
+        let path = args.out.join(format!("{}.mbox", name));
+        fs::write(&path, patch).with_context(|| format!("writing {}", path.display()))?;
+        eprintln!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+/// The commit of this repo containing the config file
+pub(super) struct SourceCommit {
+    id: String,
+    /// Whether the working tree has uncommitted changes
+    dirty: bool,
+    /// Where the repo is hosted, if known
+    location: Option<Location>,
+}
+
+/// Find the commit of this repo containing the config file, if any
+pub(super) fn source_commit(git: &dyn GitBackend, config_path: &Path) -> Option<SourceCommit> {
+    let status = git.status(Config::dir(config_path)).ok()?;
+    Some(SourceCommit {
+        id: status.head,
+        dirty: !status.changed.is_empty(),
+        location: status
+            .origin
+            .and_then(|url| Location::parse(&url, None).ok()),
+    })
+}
+
+/// Commit message matching the one used by the sync workflow.  target is
+/// the location of the downstream repo, for choosing how to link to the
+/// source commit.
+pub(super) fn commit_message(
+    source: Option<&SourceCommit>,
+    target: Option<&Location>,
+    templates: &BTreeSet<&str>,
+) -> String {
+    let mut message = String::from("Sync repo templates ⚙\n");
+    if let Some(source) = source {
+        let link = source
+            .location
+            .as_ref()
+            .filter(|_| !source.dirty)
+            .and_then(|loc| loc.commit_ref(&source.id, target));
+        match link {
+            Some(link) => message.push_str(&format!("\nSync with {}.\n", link)),
+            None if source.dirty => message.push_str(&format!(
+                "\nSync with repo-templates commit {} plus uncommitted changes.\n",
+                source.id
+            )),
+            None => message.push_str(&format!(
+                "\nSync with repo-templates commit {}.\n",
+                source.id
+            )),
+        }
+    }
+    if !templates.is_empty() {
+        message.push_str("\nChanged templates:\n");
+        for template in templates {
+            message.push_str(&format!("- {}\n", template));
+        }
+    }
+    message
+}
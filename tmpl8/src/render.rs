@@ -12,36 +12,59 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::io;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 use anyhow::{bail, Context, Result};
 use regex::Regex;
-use similar::TextDiff;
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
 use tera::Tera;
-use yansi::{Paint, Color};
+use yansi::Paint;
 
 use super::cache::*;
+use super::lint::References;
+use super::manifest::*;
 use super::schema::*;
 use super::*;
 
 pub(super) fn render(args: RenderArgs) -> Result<()> {
-    let cfg = Config::parse(&args.config)?;
-    if let Some(repo) = &args.repo {
-        if !cfg.repos.contains_key(repo) {
-            bail!("no such repo: {}", repo);
+    let cfg = Config::load(&args.config)?;
+    let only = match &args.repo {
+        Some(repo) => Some(cfg.target(repo, args.target_branch.as_deref())?.dir()),
+        None => None,
+    };
+    let rendered = do_render(&args.config, &cfg)?;
+
+    // remove files that were previously rendered into the output directory
+    // but no longer are
+    for target in cfg.targets()? {
+        let dir = target.dir();
+        let out_dir = match &only {
+            Some(only) if *only == dir => args.output.clone(),
+            Some(_) => continue, // another repo
+            None => args.output.join(&dir),
+        };
+        if let Some(manifest) = Manifest::load(&out_dir)? {
+            for path in manifest.files.keys() {
+                if !rendered.contains_key(&Path::new(&dir).join(path)) {
+                    eprintln!("Removing {}/{}", dir, path);
+                    remove_file(&out_dir.join(path))?;
+                }
+            }
         }
     }
 
-    for (mut path, data) in do_render(&args.config, &cfg)? {
-        if let Some(repo) = &args.repo {
-            path = match path.strip_prefix(repo) {
-                Ok(p) => p.into(),
-                Err(_) => continue, // file in another repo
+    for (mut path, data) in rendered {
+        if let Some(only) = &only {
+            if data.target != *only {
+                continue; // file in another repo
             }
+            path = data.relative(&path)?.into();
         }
         data.write(&args.output.join(path))?;
     }
@@ -49,42 +72,106 @@ pub(super) fn render(args: RenderArgs) -> Result<()> {
 }
 
 pub(super) fn diff(args: DiffArgs) -> Result<()> {
+    if args.no_color {
+        yansi::disable();
+    }
+
     // render
-    let cfg = Config::parse(&args.config)?;
+    let cfg = Config::load(&args.config)?;
     let rendered = do_render(&args.config, &cfg)?;
 
+    // the cache for the upstream default branch is kept separately, so
+    // we can compare against both it and the fork branch
+    let cache_dir = cache_dir(&args.config, &args.cache)?;
+    let upstream_dir = cache_dir.join(UPSTREAM_DIR);
+    let upstream_fork = ForkArgs::default();
+    // label, cache directory, and fork args for each comparison base
+    let bases = match args.base {
+        DiffBase::Fork => vec![(None, &cache_dir, &args.fork)],
+        DiffBase::Upstream => vec![(None, &upstream_dir, &upstream_fork)],
+        DiffBase::Both if !args.fork.is_set() => {
+            bail!("--base both requires a fork branch")
+        }
+        DiffBase::Both => vec![
+            (Some("pending"), &cache_dir, &args.fork),
+            (Some("upstream"), &upstream_dir, &upstream_fork),
+        ],
+    };
+
     // update Git cache
-    let cache_dir = cache_dir(&args.config)?;
-    do_update_cache(&cfg, &cache_dir, &args.fork, false)?;
+    let _lock = lock_cache(&cache_dir, !args.offline)?;
+    let paths = managed_paths(&rendered)?;
+    let mut results = Vec::new();
+    for (label, dir, fork) in bases {
+        if args.offline {
+            check_offline(&cfg, dir, args.refresh.max_age, fork, &paths)?;
+        } else {
+            do_update_cache(
+                &cfg,
+                dir,
+                args.refresh.update.jobs,
+                Some(args.refresh.max_age),
+                fork,
+                &paths,
+            )?;
+        }
+        results.push((label, compare(&cfg, &rendered, dir)?));
+    }
 
-    if args.no_color {
-        yansi::disable();
+    // warn about downstream edits relative to the merged state, or the
+    // only base we have
+    let (_, comparisons) = results.last().unwrap();
+    for cmp in comparisons {
+        if cmp.changed() && cmp.origin.map(|o| o.edited_downstream()).unwrap_or(false) {
+            warn(format!(
+                "{} was edited downstream; syncing will overwrite it",
+                cmp.path.display()
+            ));
+        }
     }
-    for (path, new_contents) in &rendered {
-        let cache_path = cache_dir.join(path);
-        let (old_path, old_contents) = match fs::read_to_string(&cache_path) {
-            Ok(c) => (path.to_string_lossy().into_owned(), c),
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                ("/dev/null".to_string(), "".to_string())
+    // warn about pending changes that don't match the current render
+    if let [(_, pending), (_, upstream)] = results.as_slice() {
+        let merged: BTreeMap<&Path, &Comparison> =
+            upstream.iter().map(|c| (c.path.as_path(), c)).collect();
+        for cmp in pending {
+            let merged = merged.get(cmp.path.as_path()).and_then(|c| c.old.as_ref());
+            if cmp.old.as_ref() != merged && cmp.changed() {
+                warn(format!(
+                    "pending sync of {} is out of date",
+                    cmp.path.display()
+                ));
             }
-            Err(e) => {
-                return Err(e).with_context(|| format!("reading {}", cache_path.display()))?
+        }
+    }
+
+    // interleave comparisons against each base, file by file
+    let mut comparisons: Vec<(Option<&str>, &Comparison)> = results
+        .iter()
+        .flat_map(|(label, cmps)| cmps.iter().map(move |cmp| (*label, cmp)))
+        .collect();
+    comparisons.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+    match args.format {
+        DiffFormat::Human => {
+            for (label, cmp) in comparisons {
+                print_diff(cmp, label);
             }
-        };
-        let diff = TextDiff::from_lines(&old_contents, new_contents.as_ref())
-            .unified_diff()
-            .header(&old_path, &path.to_string_lossy())
-            .to_string();
-        if diff.is_empty() {
-            continue;
-        }
-        for (i, line) in diff.trim_end_matches('\n').split('\n').enumerate() {
-            match line.chars().next() {
-                _ if i < 2 => println!("{}", line.bold()),
-                Some('-') => println!("{}", line.red()),
-                Some('+') => println!("{}", line.green()),
-                Some('@') => println!("{}", line.cyan()),
-                _ => println!("{}", line),
+        }
+        DiffFormat::Json => {
+            let diffs = comparisons
+                .into_iter()
+                .filter_map(|(label, cmp)| FileDiff::new(cmp, label).transpose())
+                .collect::<Result<Vec<_>>>()?;
+            serde_json::to_writer_pretty(&mut io::stdout().lock(), &diffs)
+                .context("writing to stdout")?;
+            println!();
+        }
+        DiffFormat::Ndjson => {
+            for (label, cmp) in comparisons {
+                if let Some(diff) = FileDiff::new(cmp, label)? {
+                    serde_json::to_writer(&mut io::stdout().lock(), &diff)
+                        .context("writing to stdout")?;
+                    println!();
+                }
             }
         }
     }
@@ -92,7 +179,375 @@ pub(super) fn diff(args: DiffArgs) -> Result<()> {
     Ok(())
 }
 
-fn do_render(config_path: &Path, cfg: &Config) -> Result<BTreeMap<PathBuf, RenderedTemplate>> {
+pub(super) fn warn(message: String) {
+    eprintln!("{}", format!("Warning: {}", message).yellow().bold());
+}
+
+/// label: name of the comparison base, if there are several
+fn print_diff(cmp: &Comparison, label: Option<&str>) {
+    let path = cmp.path.to_string_lossy();
+    let (old_path, old_contents) = match &cmp.old {
+        Some(c) => (path.to_string(), c.contents.as_str()),
+        None => ("/dev/null".into(), ""),
+    };
+    let old_path = match label {
+        Some(label) => format!("{} ({})", old_path, label),
+        None => old_path,
+    };
+    let (new_path, new_contents) = match cmp.new {
+        Some(c) => (path.as_ref(), c.contents.as_str()),
+        None => ("/dev/null", ""),
+    };
+    let diff = TextDiff::from_lines(old_contents, new_contents)
+        .unified_diff()
+        .header(&old_path, new_path)
+        .to_string();
+    if diff.is_empty() {
+        return;
+    }
+    for (i, line) in diff.trim_end_matches('\n').split('\n').enumerate() {
+        match line.chars().next() {
+            _ if i < 2 => println!("{}", line.bold()),
+            Some('-') => println!("{}", line.red()),
+            Some('+') => println!("{}", line.green()),
+            Some('@') => println!("{}", line.cyan()),
+            _ => println!("{}", line),
+        }
+    }
+}
+
+/// Machine-readable diff of one managed file.
+#[derive(Serialize, Debug)]
+struct FileDiff<'a> {
+    /// Comparison base, if diffing against several
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base: Option<&'a str>,
+    repo: &'a str,
+    /// Target branch, if not the default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    branch: Option<&'a str>,
+    path: &'a Path,
+    template: Option<&'a str>,
+    origin: Option<ChangeOrigin>,
+    old_exists: bool,
+    new_exists: bool,
+    old_executable: bool,
+    new_executable: bool,
+    hunks: Vec<Hunk>,
+}
+
+#[derive(Serialize, Debug)]
+struct Hunk {
+    old_start: usize,
+    old_lines: usize,
+    new_start: usize,
+    new_lines: usize,
+    lines: Vec<HunkLine>,
+}
+
+#[derive(Serialize, Debug)]
+struct HunkLine {
+    tag: &'static str,
+    text: String,
+}
+
+impl<'a> FileDiff<'a> {
+    /// Return None if the file is unchanged.
+    fn new(cmp: &'a Comparison, base: Option<&'a str>) -> Result<Option<Self>> {
+        if !cmp.changed() {
+            return Ok(None);
+        }
+        let old_contents = cmp.old.as_ref().map(|c| c.contents.as_str()).unwrap_or("");
+        let new_contents = cmp.new.map(|c| c.contents.as_str()).unwrap_or("");
+        let diff = TextDiff::from_lines(old_contents, new_contents);
+        let hunks = diff
+            .grouped_ops(3)
+            .iter()
+            .map(|ops| {
+                // ops are nonempty and contiguous
+                let first = ops.first().unwrap();
+                let last = ops.last().unwrap();
+                Hunk {
+                    // 1-based, like unified diff headers
+                    old_start: first.old_range().start + 1,
+                    old_lines: last.old_range().end - first.old_range().start,
+                    new_start: first.new_range().start + 1,
+                    new_lines: last.new_range().end - first.new_range().start,
+                    lines: ops
+                        .iter()
+                        .flat_map(|op| diff.iter_changes(op))
+                        .map(|change| HunkLine {
+                            tag: match change.tag() {
+                                ChangeTag::Equal => "equal",
+                                ChangeTag::Delete => "delete",
+                                ChangeTag::Insert => "insert",
+                            },
+                            text: change.value().into(),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        Ok(Some(Self {
+            base,
+            repo: cmp.repo,
+            branch: cmp.branch,
+            path: cmp.relative()?,
+            template: cmp.template.as_deref(),
+            origin: cmp.origin,
+            old_exists: cmp.old.is_some(),
+            new_exists: cmp.new.is_some(),
+            old_executable: cmp.old.as_ref().map(|c| c.executable).unwrap_or(false),
+            new_executable: cmp.new.map(|c| c.executable).unwrap_or(false),
+            hunks,
+        }))
+    }
+}
+
+pub(super) fn check(args: CheckArgs) -> ExitCode {
+    match do_check(&args) {
+        Ok(summary) => {
+            print!("{}", summary_table(&summary));
+            if summary.values().all(|(_, changed)| *changed == 0) {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(1)
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Count of managed files and changed files, keyed by repo branch
+type CheckSummary = BTreeMap<String, (usize, usize)>;
+
+/// Compare rendered output with the cache and summarize the differences.
+fn do_check(args: &CheckArgs) -> Result<CheckSummary> {
+    // render
+    let cfg = Config::load(&args.config)?;
+    let rendered = do_render(&args.config, &cfg)?;
+
+    // update Git cache
+    let cache_dir = cache_dir(&args.config, &args.cache)?;
+    let _lock = lock_cache(&cache_dir, !args.offline)?;
+    let paths = managed_paths(&rendered)?;
+    if args.offline {
+        check_offline(&cfg, &cache_dir, args.refresh.max_age, &args.fork, &paths)?;
+    } else {
+        do_update_cache(
+            &cfg,
+            &cache_dir,
+            args.refresh.update.jobs,
+            Some(args.refresh.max_age),
+            &args.fork,
+            &paths,
+        )?;
+    }
+
+    // count files and changed files per repo branch
+    let mut summary: CheckSummary = cfg
+        .targets()?
+        .iter()
+        .map(|target| (target.dir(), (0, 0)))
+        .collect();
+    for cmp in compare(&cfg, &rendered, &cache_dir)? {
+        let counts = summary
+            .get_mut(&cmp.target)
+            .with_context(|| format!("no such repo: {}", cmp.target))?;
+        if cmp.new.is_some() {
+            counts.0 += 1;
+        }
+        if cmp.changed() {
+            counts.1 += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+fn summary_table(summary: &CheckSummary) -> String {
+    let width = summary
+        .keys()
+        .map(|name| name.len())
+        .chain(["REPO".len()])
+        .max()
+        .unwrap();
+    let mut ret = format!(
+        "{:width$}  {:>5}  {:>7}  STATUS\n",
+        "REPO", "FILES", "CHANGED"
+    );
+    for (name, (files, changed)) in summary {
+        let status = if *changed > 0 {
+            "drift"
+        } else if *files > 0 {
+            "ok"
+        } else {
+            "unmanaged"
+        };
+        ret.push_str(&format!(
+            "{name:width$}  {files:>5}  {changed:>7}  {status}\n"
+        ));
+    }
+    ret
+}
+
+/// A managed file paired with its current contents in the cache
+pub(super) struct Comparison<'a> {
+    pub(super) path: PathBuf,
+    pub(super) repo: &'a str,
+    pub(super) branch: Option<&'a str>,
+    /// Directory of the repo branch, from Target::dir()
+    pub(super) target: String,
+    pub(super) template: Option<String>,
+    old: Option<CachedFile>,
+    /// None if the file is no longer managed and should be deleted
+    pub(super) new: Option<&'a RenderedTemplate>,
+    /// None if unchanged or if there's no record of the last sync
+    origin: Option<ChangeOrigin>,
+}
+
+impl Comparison<'_> {
+    pub(super) fn changed(&self) -> bool {
+        match (&self.old, self.new) {
+            (Some(old), Some(new)) => {
+                old.contents != new.contents || old.executable != new.executable
+            }
+            (None, None) => false,
+            _ => true,
+        }
+    }
+
+    /// Path of the file within its repo
+    pub(super) fn relative(&self) -> Result<&Path> {
+        self.path
+            .strip_prefix(&self.target)
+            .with_context(|| format!("{} not in {}", self.path.display(), self.target))
+    }
+}
+
+/// Compare rendered files against the cache, including files listed in
+/// the cached manifest that are no longer rendered.
+pub(super) fn compare<'a>(
+    cfg: &'a Config,
+    rendered: &'a BTreeMap<PathBuf, RenderedTemplate>,
+    cache_dir: &Path,
+) -> Result<Vec<Comparison<'a>>> {
+    // manifests record the hashes of the last synced render
+    let mut manifests = BTreeMap::new();
+    for target in cfg.targets()? {
+        let dir = target.dir();
+        if let Some(manifest) = Manifest::load(&cache_dir.join(&dir))? {
+            manifests.insert(dir, (target, manifest));
+        }
+    }
+    let base_hash = |target: &str, path: &Path| -> Option<&str> {
+        let path = path.strip_prefix(target).ok()?.to_str()?;
+        manifests
+            .get(target)?
+            .1
+            .files
+            .get(path)
+            .map(|e| e.sha256.as_str())
+    };
+
+    let mut ret = Vec::new();
+    for (path, new) in rendered {
+        let old = read_cached(cache_dir, path)?;
+        let origin = base_hash(&new.target, path)
+            .and_then(|base| ChangeOrigin::new(base, old.as_ref(), Some(new)));
+        ret.push(Comparison {
+            path: path.clone(),
+            repo: &new.repo,
+            branch: new.branch.as_deref(),
+            target: new.target.clone(),
+            template: new.template.clone(),
+            old,
+            new: Some(new),
+            origin,
+        });
+    }
+    for (dir, (target, manifest)) in &manifests {
+        for (path, entry) in &manifest.files {
+            let path = Path::new(dir).join(path);
+            if rendered.contains_key(&path) {
+                continue;
+            }
+            if let Some(old) = read_cached(cache_dir, &path)? {
+                let origin = ChangeOrigin::new(&entry.sha256, Some(&old), None);
+                ret.push(Comparison {
+                    path,
+                    repo: target.repo_name,
+                    branch: target.branch_name(),
+                    target: dir.clone(),
+                    template: Some(entry.template.clone()),
+                    old: Some(old),
+                    new: None,
+                    origin,
+                });
+            }
+        }
+    }
+    ret.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(ret)
+}
+
+/// Which side changed a file since the last sync
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ChangeOrigin {
+    Template,
+    Downstream,
+    Both,
+}
+
+impl ChangeOrigin {
+    /// Classify a change relative to the hash of the last synced render.
+    /// Return None if neither side has changed.
+    fn new(base: &str, old: Option<&CachedFile>, new: Option<&RenderedTemplate>) -> Option<Self> {
+        let downstream = old.map(|c| hash(&c.contents) != base).unwrap_or(true);
+        let template = new.map(|c| hash(&c.contents) != base).unwrap_or(true);
+        match (template, downstream) {
+            (true, true) => Some(Self::Both),
+            (true, false) => Some(Self::Template),
+            (false, true) => Some(Self::Downstream),
+            (false, false) => None,
+        }
+    }
+
+    fn edited_downstream(&self) -> bool {
+        matches!(self, Self::Downstream | Self::Both)
+    }
+}
+
+#[derive(PartialEq, Eq)]
+struct CachedFile {
+    contents: String,
+    executable: bool,
+}
+
+/// Read a file from the cache, returning None if it doesn't exist.
+fn read_cached(cache_dir: &Path, path: &Path) -> Result<Option<CachedFile>> {
+    read_file(&cache_dir.join(path))
+}
+
+fn read_file(path: &Path) -> Result<Option<CachedFile>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    let meta = fs::metadata(path).with_context(|| format!("statting {}", path.display()))?;
+    Ok(Some(CachedFile {
+        contents,
+        executable: meta.permissions().mode() & 0o111 != 0,
+    }))
+}
+
+/// Parse all templates listed in the config
+pub(super) fn load_templates(config_path: &Path, cfg: &Config) -> Result<Tera> {
     let mut tera = Tera::default();
     tera.add_template_files(
         cfg.templates
@@ -101,37 +556,246 @@ fn do_render(config_path: &Path, cfg: &Config) -> Result<BTreeMap<PathBuf, Rende
             .collect::<Result<Vec<_>>>()?,
     )
     .context("parsing templates")?;
+    Ok(tera)
+}
+
+pub(super) fn do_render(
+    config_path: &Path,
+    cfg: &Config,
+) -> Result<BTreeMap<PathBuf, RenderedTemplate>> {
+    let tera = load_templates(config_path, cfg)?;
 
-    let ctx = cfg.vars.to_context()?;
     let mut rendered = BTreeMap::new();
+    // target directory -> target and its manifest
+    let mut manifests: BTreeMap<String, (Target, Manifest)> = BTreeMap::new();
     for template in &cfg.templates {
-        let tmpl_cfg = TemplateConfig::parse(&template_config_path(config_path, template)?)?;
-        let mut ctx = ctx.clone();
-        ctx.extend(tmpl_cfg.vars.to_context()?);
+        let tmpl_path = template_path(config_path, template)?;
+        let tmpl_cfg_path = template_config_path(config_path, template)?;
+        let tmpl_cfg = TemplateConfig::load(&tmpl_cfg_path, cfg)?;
+        tmpl_cfg
+            .validate_schema()
+            .with_context(|| format!("validating schema in {}", tmpl_cfg_path.display()))?;
+        let defaults = tmpl_cfg.defaults().to_context()?;
 
         for file in &tmpl_cfg.files {
-            let repo = file.repo(cfg)?;
-            let mut ctx = ctx.clone();
-            ctx.extend(repo.vars.to_context()?);
-            ctx.extend(file.vars.to_context()?);
-
-            let result = RenderedTemplate::new(&tera, template, &ctx)
-                .with_context(|| format!("rendering {}", file.path().display()))?;
-            if rendered.insert(file.path(), result).is_some() {
-                bail!("multiple attempts to write to {}", file.path().display());
+            // render the file into each branch of its repo
+            for target in cfg.repo_targets(&file.repo)? {
+                let path = Path::new(&target.dir()).join(&file.path);
+                tmpl_cfg.validate(cfg, file, &target).with_context(|| {
+                    format!(
+                        "validating variables for {} in {}",
+                        path.display(),
+                        tmpl_cfg_path.display()
+                    )
+                })?;
+                let ctx = target_context(cfg, &target, &tmpl_cfg.vars, &file.vars, &defaults)
+                    .with_context(|| format!("rendering {}", path.display()))?;
+
+                let result = RenderedTemplate::new(&tera, template, &tmpl_path, &target, &ctx)
+                    .with_context(|| format!("rendering {}", path.display()))?;
+                manifests
+                    .entry(target.dir())
+                    .or_insert_with(|| (target, Manifest::default()))
+                    .1
+                    .files
+                    .insert(
+                        file.path.clone(),
+                        ManifestEntry {
+                            template: template.clone(),
+                            sha256: hash(&result.contents),
+                        },
+                    );
+                if rendered.insert(path.clone(), result).is_some() {
+                    bail!("multiple attempts to write to {}", path.display());
+                }
             }
         }
     }
+
+    // record managed files in each branch
+    for (dir, (target, manifest)) in manifests {
+        let path = Path::new(&dir).join(MANIFEST_PATH);
+        let result = RenderedTemplate {
+            contents: manifest.to_json()?,
+            executable: false,
+            repo: target.repo_name.into(),
+            branch: target.branch_name().map(String::from),
+            target: dir,
+            template: None,
+        };
+        if rendered.insert(path.clone(), result).is_some() {
+            bail!("multiple attempts to write to {}", path.display());
+        }
+    }
     Ok(rendered)
 }
 
-struct RenderedTemplate {
-    contents: String,
-    executable: bool,
+/// Full context for rendering into a repo branch: global, template, preset,
+/// repo, branch, and file vars on top of the schema defaults, with unset
+/// vars filled in from the presets' computed defaults
+pub(super) fn target_context(
+    cfg: &Config,
+    target: &Target,
+    tmpl_vars: &Vars,
+    file_vars: &Vars,
+    defaults: &tera::Context,
+) -> Result<tera::Context> {
+    let mut layers = vec![
+        ("global vars".to_string(), &cfg.vars),
+        ("template vars".into(), tmpl_vars),
+    ];
+    layers.extend(target.vars());
+    layers.push(("file vars".into(), file_vars));
+    let ctx = merge_vars(&layers, defaults)?;
+    let computed = target.computed_defaults(|name| ctx.contains_key(name))?;
+    let mut full = defaults.clone();
+    full.extend(ctx);
+    // computed defaults can refer to each other
+    let computed = VarResolver::new(&computed, full.clone())
+        .and_then(|r| r.resolve())
+        .context("resolving computed defaults")?;
+    full.extend(computed);
+    Ok(full)
+}
+
+/// Merge layers of context variables, lowest precedence first, on top of
+/// defaults.  Templated string values are rendered against the variables
+/// merged so far.  Returns the merged layers without the defaults.
+pub(super) fn merge_vars(
+    layers: &[(String, &Vars)],
+    defaults: &tera::Context,
+) -> Result<tera::Context> {
+    let mut ctx = tera::Context::new();
+    for (desc, vars) in layers {
+        let mut lower = defaults.clone();
+        lower.extend(ctx.clone());
+        ctx.extend(
+            VarResolver::new(vars, lower)
+                .and_then(|r| r.resolve())
+                .with_context(|| format!("resolving {}", desc))?,
+        );
+    }
+    Ok(ctx)
+}
+
+/// Renders the templated values in one layer of vars.  Values can refer to
+/// other variables in the same layer; a value referring to its own name
+/// sees the value from lower layers.
+struct VarResolver {
+    /// Templated values, keyed by variable name
+    tera: Tera,
+    /// Lower layers plus the values resolved so far
+    ctx: tera::Context,
+    /// Values in this layer
+    resolved: tera::Context,
+    done: BTreeSet<String>,
+    /// Variables being resolved, for detecting cycles
+    stack: Vec<String>,
+}
+
+impl VarResolver {
+    fn new(vars: &Vars, lower: tera::Context) -> Result<Self> {
+        let mut ret = Self {
+            tera: Tera::default(),
+            ctx: lower,
+            resolved: tera::Context::new(),
+            done: BTreeSet::new(),
+            stack: Vec::new(),
+        };
+        for (name, template) in vars.templated() {
+            ret.tera
+                .add_raw_template(name, template)
+                .with_context(|| format!("parsing variable {}", name))?;
+        }
+        for (name, value) in vars.iter() {
+            if !ret.tera.templates.contains_key(name) {
+                ret.ctx.insert(name, value);
+                ret.resolved.insert(name, value);
+            }
+        }
+        Ok(ret)
+    }
+
+    fn resolve(mut self) -> Result<tera::Context> {
+        // sorted for deterministic error messages
+        let names: BTreeSet<String> = self.tera.templates.keys().cloned().collect();
+        for name in names {
+            self.resolve_one(&name)?;
+        }
+        Ok(self.resolved)
+    }
+
+    fn resolve_one(&mut self, name: &str) -> Result<()> {
+        if self.done.contains(name) {
+            return Ok(());
+        }
+        if let Some(pos) = self.stack.iter().position(|n| n == name) {
+            let mut cycle = self.stack[pos..].to_vec();
+            cycle.push(name.into());
+            bail!("variables refer to each other: {}", cycle.join(" -> "));
+        }
+        self.stack.push(name.into());
+        let refs: Vec<String> = References::new(&self.tera.templates[name].ast)
+            .all()
+            .filter(|r| *r != name && self.tera.templates.contains_key(*r))
+            .cloned()
+            .collect();
+        for dep in refs {
+            self.resolve_one(&dep)?;
+        }
+        self.stack.pop();
+
+        let value = self
+            .tera
+            .render(name, &self.ctx)
+            .with_context(|| format!("rendering variable {}", name))?;
+        self.ctx.insert(name, &value);
+        self.resolved.insert(name, &value);
+        self.done.insert(name.into());
+        Ok(())
+    }
+}
+
+/// Render the templates and list the resulting files in each repo
+pub(super) fn render_managed_paths(config_path: &Path, cfg: &Config) -> Result<ManagedPaths> {
+    managed_paths(&do_render(config_path, cfg)?)
+}
+
+pub(super) fn managed_paths(
+    rendered: &BTreeMap<PathBuf, RenderedTemplate>,
+) -> Result<ManagedPaths> {
+    let mut ret = ManagedPaths::new();
+    for (path, result) in rendered {
+        let rel = result.relative(path)?;
+        ret.entry(result.target.clone())
+            .or_default()
+            .insert(rel.to_string_lossy().into_owned());
+    }
+    Ok(ret)
+}
+
+pub(super) struct RenderedTemplate {
+    pub(super) contents: String,
+    pub(super) executable: bool,
+    pub(super) repo: String,
+    /// None for the remote's default branch
+    pub(super) branch: Option<String>,
+    /// Directory of the repo branch, from Target::dir()
+    pub(super) target: String,
+    /// None for generated files such as the manifest
+    pub(super) template: Option<String>,
 }
 
 impl RenderedTemplate {
-    fn new(tera: &Tera, template: &str, ctx: &tera::Context) -> Result<Self> {
+    /// template is the name from the config file; path is the template
+    /// file, which is checked for the executable bit
+    fn new(
+        tera: &Tera,
+        template: &str,
+        path: &Path,
+        target: &Target,
+        ctx: &tera::Context,
+    ) -> Result<Self> {
         let output = tera.render(template, ctx)?;
 
         // clean up some common rendering artifacts to ease template writing
@@ -140,31 +804,42 @@ impl RenderedTemplate {
         // collapse 2 or more trailing newlines into 1
         let output = Regex::new("\n{2,}$").unwrap().replace_all(&output, "\n");
 
-        let meta = fs::metadata(template).with_context(|| format!("statting {}", template))?;
+        let meta = fs::metadata(path).with_context(|| format!("statting {}", path.display()))?;
 
         Ok(Self {
             contents: output.to_string(),
             executable: meta.permissions().mode() & 0o111 != 0,
+            repo: target.repo_name.into(),
+            branch: target.branch_name().map(String::from),
+            target: target.dir(),
+            template: Some(template.into()),
         })
     }
 
-    fn write(&self, path: &Path) -> Result<()> {
+    /// Strip the target directory from the rendered path of this file
+    pub(super) fn relative<'p>(&self, path: &'p Path) -> Result<&'p Path> {
+        path.strip_prefix(&self.target)
+            .with_context(|| format!("{} not in {}", path.display(), self.target))
+    }
+
+    /// Whether the file at path already has these contents
+    pub(super) fn matches(&self, path: &Path) -> Result<bool> {
+        Ok(read_file(path)?
+            .map(|old| old.contents == self.contents && old.executable == self.executable)
+            .unwrap_or(false))
+    }
+
+    pub(super) fn write(&self, path: &Path) -> Result<()> {
         let dir = path
             .parent()
             .with_context(|| format!("getting parent of {}", path.display()))?;
         fs::create_dir_all(dir).with_context(|| format!("creating directory {}", dir.display()))?;
         // don't reuse existing file permissions
-        match fs::remove_file(&path) {
-            Ok(()) => (),
-            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
-            Err(e) => {
-                return Err(e).with_context(|| format!("removing existing file {}", path.display()))
-            }
-        }
-        fs::write(&path, &self.contents.as_bytes())
+        remove_file(path)?;
+        fs::write(path, self.contents.as_bytes())
             .with_context(|| format!("writing file {}", path.display()))?;
         if self.executable {
-            let mut mode = fs::metadata(&path)
+            let mut mode = fs::metadata(path)
                 .with_context(|| format!("statting file {}", path.display()))?
                 .permissions()
                 .mode();
@@ -174,16 +849,19 @@ impl RenderedTemplate {
                     mode |= 1 << shift;
                 }
             }
-            fs::set_permissions(&path, fs::Permissions::from_mode(mode))
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))
                 .with_context(|| format!("setting {} executable", path.display()))?;
         }
         Ok(())
     }
 }
 
-impl AsRef<String> for RenderedTemplate {
-    fn as_ref(&self) -> &String {
-        &self.contents
+/// Remove a file, ignoring it if missing
+pub(super) fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("removing existing file {}", path.display())),
     }
 }
 
@@ -194,7 +872,7 @@ fn template_path(config_path: &Path, template: &str) -> Result<PathBuf> {
         .join(template))
 }
 
-fn template_config_path(config_path: &Path, template: &str) -> Result<PathBuf> {
+pub(super) fn template_config_path(config_path: &Path, template: &str) -> Result<PathBuf> {
     let path = template_path(config_path, template)?;
     let parent = path
         .parent()
@@ -206,3 +884,277 @@ fn template_config_path(config_path: &Path, template: &str) -> Result<PathBuf> {
     filename.push(".yaml");
     Ok(parent.join(filename))
 }
+
+#[cfg(test)]
+mod test {
+    use super::super::git::DEFAULT_BRANCH;
+    use super::*;
+
+    #[test]
+    fn change_origin() {
+        let cached = |contents: &str| CachedFile {
+            contents: contents.into(),
+            executable: false,
+        };
+        let rendered = |contents: &str| RenderedTemplate {
+            contents: contents.into(),
+            executable: false,
+            repo: "repo".into(),
+            branch: None,
+            target: "repo".into(),
+            template: None,
+        };
+        let base = hash("a");
+        let origin = |old: Option<&CachedFile>, new: Option<&RenderedTemplate>| {
+            ChangeOrigin::new(&base, old, new)
+        };
+
+        assert_eq!(origin(Some(&cached("a")), Some(&rendered("a"))), None);
+        assert_eq!(
+            origin(Some(&cached("a")), Some(&rendered("b"))),
+            Some(ChangeOrigin::Template)
+        );
+        assert_eq!(
+            origin(Some(&cached("b")), Some(&rendered("a"))),
+            Some(ChangeOrigin::Downstream)
+        );
+        assert_eq!(
+            origin(Some(&cached("b")), Some(&rendered("c"))),
+            Some(ChangeOrigin::Both)
+        );
+        // deleted downstream
+        assert_eq!(
+            origin(None, Some(&rendered("a"))),
+            Some(ChangeOrigin::Downstream)
+        );
+        // no longer rendered
+        assert_eq!(
+            origin(Some(&cached("a")), None),
+            Some(ChangeOrigin::Template)
+        );
+    }
+
+    #[test]
+    fn file_diff() {
+        let old = CachedFile {
+            contents: "a\nb\nc\n".into(),
+            executable: false,
+        };
+        let new = RenderedTemplate {
+            contents: "a\nB\nc\n".into(),
+            executable: true,
+            repo: "repo".into(),
+            branch: None,
+            target: "repo".into(),
+            template: Some("t.md".into()),
+        };
+        fn cmp(old: Option<CachedFile>, new: Option<&RenderedTemplate>) -> Comparison<'_> {
+            Comparison {
+                path: "repo/t.md".into(),
+                repo: "repo",
+                branch: None,
+                target: "repo".into(),
+                template: Some("t.md".into()),
+                old,
+                new,
+                origin: None,
+            }
+        }
+
+        let changed = cmp(Some(old), Some(&new));
+        let diff = FileDiff::new(&changed, Some("upstream")).unwrap().unwrap();
+        assert_eq!(
+            serde_json::to_value(&diff).unwrap(),
+            serde_json::json!({
+                "base": "upstream",
+                "repo": "repo",
+                "path": "t.md",
+                "template": "t.md",
+                "origin": null,
+                "old_exists": true,
+                "new_exists": true,
+                "old_executable": false,
+                "new_executable": true,
+                "hunks": [{
+                    "old_start": 1,
+                    "old_lines": 3,
+                    "new_start": 1,
+                    "new_lines": 3,
+                    "lines": [
+                        {"tag": "equal", "text": "a\n"},
+                        {"tag": "delete", "text": "b\n"},
+                        {"tag": "insert", "text": "B\n"},
+                        {"tag": "equal", "text": "c\n"},
+                    ],
+                }],
+            })
+        );
+        // NDJSON records are single lines
+        assert!(!serde_json::to_string(&diff).unwrap().contains('\n'));
+
+        // new file has one hunk starting before the beginning of the old
+        let added = cmp(None, Some(&new));
+        let diff = FileDiff::new(&added, None).unwrap().unwrap();
+        let value = serde_json::to_value(&diff).unwrap();
+        assert!(value.get("base").is_none());
+        assert_eq!(value["old_exists"], false);
+        assert_eq!(value["hunks"][0]["old_start"], 1);
+        assert_eq!(value["hunks"][0]["old_lines"], 0);
+        assert_eq!(value["hunks"][0]["new_lines"], 3);
+
+        // unchanged files are omitted
+        let same = CachedFile {
+            contents: new.contents.clone(),
+            executable: true,
+        };
+        assert!(FileDiff::new(&cmp(Some(same), Some(&new)), None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn check_drift() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = temp.path().join("config.yaml");
+        let cache = temp.path().join("cache");
+        fs::write(
+            &config,
+            "templates: [t.md]\nrepos:\n  a: {url: https://example.com/a}\n  b: {url: https://example.com/b}\n",
+        )
+        .unwrap();
+        fs::write(temp.path().join("t.md"), "hello\n").unwrap();
+        fs::write(
+            temp.path().join("t.yaml"),
+            "files:\n  - repo: a\n    path: t.md\n",
+        )
+        .unwrap();
+
+        // populate the cache with the current render
+        render(RenderArgs::parse_from([
+            "render".as_ref(),
+            cache.as_os_str(),
+            "-c".as_ref(),
+            config.as_os_str(),
+        ]))
+        .unwrap();
+        for name in ["a", "b"] {
+            let dir = cache.join(name);
+            fs::create_dir_all(dir.join(".git")).unwrap();
+            fs::write(dir.join(STAMP_PATH), DEFAULT_BRANCH).unwrap();
+            fs::write(dir.join(SPARSE_PATH), format!("{}\nt.md\n", MANIFEST_PATH)).unwrap();
+        }
+
+        let args = || {
+            CheckArgs::parse_from([
+                "check".as_ref(),
+                "-c".as_ref(),
+                config.as_os_str(),
+                "--cache-dir".as_ref(),
+                cache.as_os_str(),
+                "--offline".as_ref(),
+            ])
+        };
+        let exit_code = |code: ExitCode| format!("{:?}", code);
+
+        let summary = do_check(&args()).unwrap();
+        assert_eq!(
+            summary_table(&summary),
+            "REPO  FILES  CHANGED  STATUS\n\
+             a         2        0  ok\n\
+             b         0        0  unmanaged\n"
+        );
+        assert_eq!(exit_code(check(args())), exit_code(ExitCode::SUCCESS));
+
+        fs::write(cache.join("a/t.md"), "edited\n").unwrap();
+        let summary = do_check(&args()).unwrap();
+        assert_eq!(summary["a"], (2, 1));
+        assert!(summary_table(&summary).contains("\na         2        1  drift\n"));
+        assert_eq!(exit_code(check(args())), exit_code(ExitCode::from(1)));
+
+        fs::remove_file(&config).unwrap();
+        assert_eq!(exit_code(check(args())), exit_code(ExitCode::from(2)));
+    }
+
+    #[test]
+    fn templated_vars() {
+        let vars = |yaml: &str| serde_yaml::from_str::<Vars>(yaml).unwrap();
+        let global = vars("{org: coreos, stream: stable}");
+        let repo = vars(
+            "{git_repo: zincati, quay_repo: '{{ org }}/{{ name }}', name: '{{ git_repo }}', stream: 'next-{{ stream }}'}",
+        );
+        let mut defaults = tera::Context::new();
+        defaults.insert("org", "default");
+        let ctx = merge_vars(
+            &[("global vars".into(), &global), ("repo vars".into(), &repo)],
+            &defaults,
+        )
+        .unwrap();
+        assert_eq!(ctx.get("quay_repo").unwrap(), "coreos/zincati");
+        assert_eq!(ctx.get("stream").unwrap(), "next-stable");
+
+        let cycle = vars("{a: '{{ b }}', b: '{{ c }}', c: '{{ a }}'}");
+        assert_eq!(
+            format!(
+                "{:#}",
+                merge_vars(&[("repo vars".into(), &cycle)], &defaults).unwrap_err()
+            ),
+            "resolving repo vars: variables refer to each other: a -> b -> c -> a"
+        );
+        let undefined = vars("{a: '{{ missing }}'}");
+        assert!(format!(
+            "{:#}",
+            merge_vars(&[("file vars".into(), &undefined)], &defaults).unwrap_err()
+        )
+        .starts_with("resolving file vars: rendering variable a: "));
+    }
+
+    #[test]
+    fn computed_defaults() {
+        let cfg: Config = serde_yaml::from_str(
+            "
+templates: []
+presets:
+  rust:
+    defaults: {package: 'rust-{{ crate }}', srpm: '{{ package }}', spec: '{{ srpm }}.spec'}
+  bad:
+    defaults: {package: '{{ missing }}'}
+repos:
+  a: {url: https://example.com/a, presets: [rust], vars: {crate: a}}
+  b: {url: https://example.com/b, presets: [rust], vars: {crate: b, srpm: b}}
+  c: {url: https://example.com/c, presets: [bad]}
+",
+        )
+        .unwrap();
+        let no_vars = Vars::default();
+        let ctx = |repo: &str, defaults: &tera::Context| {
+            target_context(
+                &cfg,
+                &cfg.target(repo, None).unwrap(),
+                &no_vars,
+                &no_vars,
+                defaults,
+            )
+        };
+        let mut defaults = tera::Context::new();
+        defaults.insert("package", "ignored");
+        defaults.insert("other", "default");
+
+        // defaults can build on each other, and override schema defaults
+        let a = ctx("a", &defaults).unwrap();
+        assert_eq!(a.get("srpm").unwrap(), "rust-a");
+        assert_eq!(a.get("spec").unwrap(), "rust-a.spec");
+        assert_eq!(a.get("other").unwrap(), "default");
+        // and on explicitly set vars
+        let b = ctx("b", &defaults).unwrap();
+        assert_eq!(b.get("package").unwrap(), "rust-b");
+        assert_eq!(b.get("spec").unwrap(), "b.spec");
+
+        let err = format!("{:#}", ctx("c", &tera::Context::new()).unwrap_err());
+        assert!(
+            err.starts_with("resolving computed defaults: rendering variable package: ")
+                && err.contains("Variable `missing` not found"),
+            "{}",
+            err
+        );
+    }
+}
@@ -12,30 +12,99 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs::OpenOptions;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use super::forge::Forge;
+
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    // globs, relative to this file, of fragments contributing repos and vars
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
     pub repos: BTreeMap<String, Repo>,
     pub templates: Vec<String>,
     #[serde(default)]
     pub vars: Vars,
+    // named sets of vars that repos can opt into
+    #[serde(default)]
+    pub presets: BTreeMap<String, Preset>,
+    // global vars from each file, starting with this one
+    #[serde(skip)]
+    pub var_files: Vec<(PathBuf, Vars)>,
+}
+
+/// An included part of Config
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ConfigFragment {
+    #[serde(default)]
+    repos: BTreeMap<String, Repo>,
+    #[serde(default)]
+    vars: Vars,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    // overrides TemplateConfig.vars
+    #[serde(default)]
+    pub vars: Vars,
+    // Tera templates for vars that aren't otherwise set, rendered with the
+    // file's context; overrides TemplateConfig.schema defaults
+    #[serde(default)]
+    pub defaults: Vars,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Repo {
     pub url: String,
-    // overrides TemplateConfig.vars
+    // inferred from the URL if not specified
+    #[serde(default)]
+    pub forge: Option<Forge>,
+    // for selecting repos in TemplateConfig.files
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // names of Config.presets, later ones taking precedence
+    #[serde(default)]
+    pub presets: Vec<String>,
+    // overrides presets
     #[serde(default)]
     pub vars: Vars,
+    // branches to render into, instead of the remote's default branch
+    #[serde(default)]
+    pub branches: Vec<Branch>,
+    // included file defining the repo, or None for the top-level config
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Branch {
+    pub name: String,
+    // overrides Repo.vars
+    #[serde(default)]
+    pub vars: Vars,
+}
+
+/// A branch of a repo that files are rendered into
+#[derive(Clone, Copy, Debug)]
+pub struct Target<'a> {
+    pub repo_name: &'a str,
+    pub repo: &'a Repo,
+    pub presets: &'a BTreeMap<String, Preset>,
+    /// None for the remote's default branch
+    pub branch: Option<&'a Branch>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -45,19 +114,57 @@ pub struct TemplateConfig {
     // overrides Config.vars
     #[serde(default)]
     pub vars: Vars,
+    // declarations of variables read by the template
+    #[serde(default)]
+    pub schema: BTreeMap<String, VarSchema>,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
+pub struct VarSchema {
+    #[serde(rename = "type")]
+    pub kind: VarType,
+    // overridden by Config.vars
+    pub default: Option<serde_yaml::Value>,
+    #[serde(default)]
+    pub required: bool,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VarType {
+    String,
+    Bool,
+    Int,
+    List,
+    Map,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct File {
+    // exactly one of repo and repos; repos is expanded into multiple Files
+    // with repo set
+    #[serde(default)]
     pub repo: String,
+    #[serde(default)]
+    repos: Option<RepoSelector>,
     pub path: String,
     // overrides Repo.vars
     #[serde(default)]
     pub vars: Vars,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RepoSelector {
+    pub tag: String,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub struct Vars {
     #[serde(flatten)]
     vars: BTreeMap<String, serde_yaml::Value>,
@@ -78,9 +185,376 @@ where
 }
 
 impl Parseable for Config {}
+impl Parseable for ConfigFragment {}
 impl Parseable for TemplateConfig {}
 
+impl TemplateConfig {
+    /// Parse template config and expand repo selectors
+    pub fn load(path: &Path, cfg: &Config) -> Result<Self> {
+        let mut ret = Self::parse(path)?;
+        ret.files = ret
+            .files
+            .into_iter()
+            .map(|file| file.expand(cfg))
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("expanding repo selectors in {}", path.display()))?
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(ret)
+    }
+
+    /// Check that declared defaults match their declared types
+    pub fn validate_schema(&self) -> Result<()> {
+        for (name, decl) in &self.schema {
+            if let Some(default) = &decl.default {
+                decl.kind
+                    .check(default)
+                    .with_context(|| format!("default for variable {}", name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Variables with declared defaults
+    pub fn defaults(&self) -> Vars {
+        Vars {
+            vars: self
+                .schema
+                .iter()
+                .filter_map(|(name, decl)| Some((name.clone(), decl.default.clone()?)))
+                .collect(),
+        }
+    }
+
+    /// Check variables for one file in one branch against the schema
+    pub fn validate(&self, cfg: &Config, file: &File, target: &Target) -> Result<()> {
+        // highest precedence first
+        let mut layers = vec![("file vars".to_string(), &file.vars)];
+        layers.extend(target.vars().into_iter().rev());
+        layers.push(("template vars".into(), &self.vars));
+        layers.push(("global vars".into(), &cfg.vars));
+        let presets: Vec<_> = target.presets().collect();
+        for (name, decl) in &self.schema {
+            match layers
+                .iter()
+                .find_map(|(desc, vars)| Some((desc, vars.vars.get(name)?)))
+            {
+                Some((desc, value)) => decl
+                    .kind
+                    .check(value)
+                    .with_context(|| format!("variable {} from {}", name, desc))?,
+                // computed defaults always render to strings
+                None if presets.iter().any(|(_, p)| p.defaults.contains(name)) => decl
+                    .kind
+                    .check(&serde_yaml::Value::String(String::new()))
+                    .with_context(|| format!("variable {} from preset defaults", name))?,
+                None if decl.required && decl.default.is_none() => {
+                    bail!("required variable {} is not set", name)
+                }
+                None => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VarType {
+    fn check(&self, value: &serde_yaml::Value) -> Result<()> {
+        use serde_yaml::Value;
+        let ok = match self {
+            Self::String => value.is_string(),
+            Self::Bool => value.is_bool(),
+            Self::Int => value.is_i64() || value.is_u64(),
+            Self::List => value.is_sequence(),
+            Self::Map => value.is_mapping(),
+        };
+        if !ok {
+            let found = match value {
+                Value::Null => "null",
+                Value::Bool(_) => "bool",
+                Value::Number(n) if n.is_f64() => "float",
+                Value::Number(_) => "int",
+                Value::String(_) => "string",
+                Value::Sequence(_) => "list",
+                Value::Mapping(_) => "map",
+                Value::Tagged(_) => "tagged value",
+            };
+            bail!("expected {}, found {}", self, found);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for VarType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::String => "string",
+            Self::Bool => "bool",
+            Self::Int => "int",
+            Self::List => "list",
+            Self::Map => "map",
+        };
+        f.write_str(s)
+    }
+}
+
+impl Config {
+    /// Directory containing the config file at `path`
+    pub fn dir(path: &Path) -> &Path {
+        match path.parent() {
+            // an empty parent is the current directory
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    /// Parse config and merge in included fragments
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut ret = Self::parse(path)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        // where each repo and var was defined, for reporting duplicates
+        let mut repo_files: BTreeMap<String, PathBuf> = ret
+            .repos
+            .keys()
+            .map(|name| (name.clone(), path.into()))
+            .collect();
+        let mut var_files: BTreeMap<String, PathBuf> = ret
+            .vars
+            .names()
+            .map(|name| (name.into(), path.into()))
+            .collect();
+        ret.var_files.push((path.into(), ret.vars.clone()));
+        let mut seen = BTreeSet::from([path.to_path_buf()]);
+        for pattern in &ret.include {
+            let full = dir.join(pattern);
+            let full = full
+                .to_str()
+                .with_context(|| format!("include path {} is not UTF-8", full.display()))?;
+            let mut matched = false;
+            for entry in
+                glob::glob(full).with_context(|| format!("parsing include pattern {}", pattern))?
+            {
+                let file = entry.with_context(|| format!("expanding include {}", pattern))?;
+                matched = true;
+                if !seen.insert(file.clone()) {
+                    continue;
+                }
+                let fragment = ConfigFragment::parse(&file)?;
+                for (name, mut repo) in fragment.repos {
+                    if let Some(prev) = repo_files.get(&name) {
+                        bail!(
+                            "repo {} is defined in both {} and {}",
+                            name,
+                            prev.display(),
+                            file.display()
+                        );
+                    }
+                    repo.source = Some(file.clone());
+                    repo_files.insert(name.clone(), file.clone());
+                    ret.repos.insert(name, repo);
+                }
+                for (name, value) in fragment.vars.iter() {
+                    if let Some(prev) = var_files.get(name) {
+                        bail!(
+                            "variable {} is defined in both {} and {}",
+                            name,
+                            prev.display(),
+                            file.display()
+                        );
+                    }
+                    var_files.insert(name.into(), file.clone());
+                    ret.vars.vars.insert(name.into(), value.clone());
+                }
+                if !fragment.vars.vars.is_empty() {
+                    ret.var_files.push((file, fragment.vars));
+                }
+            }
+            if !matched {
+                bail!("include {} matched no files", pattern);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// File defining a global var, if known
+    pub fn var_source(&self, name: &str) -> Option<&Path> {
+        self.var_files
+            .iter()
+            .find(|(_, vars)| vars.contains(name))
+            .map(|(file, _)| file.as_path())
+    }
+
+    /// All branches of all repos that files are rendered into
+    pub fn targets(&self) -> Result<Vec<Target<'_>>> {
+        let mut ret = Vec::new();
+        for (name, repo) in &self.repos {
+            ret.extend(repo.targets(name, &self.presets)?);
+        }
+        Ok(ret)
+    }
+
+    /// All branches of one repo that files are rendered into
+    pub fn repo_targets(&self, repo_name: &str) -> Result<Vec<Target<'_>>> {
+        let (repo_name, repo) = self
+            .repos
+            .get_key_value(repo_name)
+            .with_context(|| format!("no such repo: {}", repo_name))?;
+        repo.targets(repo_name, &self.presets)
+    }
+
+    /// Find the branch of a repo to render into.  branch can be omitted
+    /// if the repo has at most one.
+    pub fn target(&self, repo_name: &str, branch: Option<&str>) -> Result<Target<'_>> {
+        let targets = self.repo_targets(repo_name)?;
+        match branch {
+            Some(branch) => targets
+                .into_iter()
+                .find(|t| t.branch_name() == Some(branch))
+                .with_context(|| format!("repo {} has no branch {}", repo_name, branch)),
+            None if targets.len() == 1 => Ok(targets[0]),
+            None => bail!(
+                "repo {} has multiple branches; specify --target-branch",
+                repo_name
+            ),
+        }
+    }
+}
+
+impl Repo {
+    /// The branches of this repo that files are rendered into
+    fn targets<'a>(
+        &'a self,
+        name: &'a str,
+        presets: &'a BTreeMap<String, Preset>,
+    ) -> Result<Vec<Target<'a>>> {
+        for preset in &self.presets {
+            if !presets.contains_key(preset) {
+                bail!("repo {}: no such preset {}", name, preset);
+            }
+        }
+        if self.branches.is_empty() {
+            return Ok(vec![Target {
+                repo_name: name,
+                repo: self,
+                presets,
+                branch: None,
+            }]);
+        }
+        let mut seen = BTreeSet::new();
+        for branch in &self.branches {
+            // the name becomes part of a directory name
+            if branch.name.is_empty() || branch.name.contains('/') {
+                bail!("repo {}: unsupported branch name {:?}", name, branch.name);
+            }
+            if !seen.insert(&branch.name) {
+                bail!("repo {}: duplicate branch {}", name, branch.name);
+            }
+        }
+        Ok(self
+            .branches
+            .iter()
+            .map(|branch| Target {
+                repo_name: name,
+                repo: self,
+                presets,
+                branch: Some(branch),
+            })
+            .collect())
+    }
+}
+
+impl<'a> Target<'a> {
+    /// Directory holding this branch in render output and the cache
+    pub fn dir(&self) -> String {
+        match self.branch {
+            Some(branch) => format!("{}@{}", self.repo_name, branch.name),
+            None => self.repo_name.into(),
+        }
+    }
+
+    pub fn branch_name(&self) -> Option<&'a str> {
+        self.branch.map(|b| b.name.as_str())
+    }
+
+    /// The repo's presets, lowest precedence first
+    pub fn presets(&self) -> impl Iterator<Item = (&'a str, &'a Preset)> + 'a {
+        let presets = self.presets;
+        self.repo
+            .presets
+            .iter()
+            .map(move |name| (name.as_str(), &presets[name]))
+    }
+
+    /// Context variables, of lowest to highest precedence, that this
+    /// target adds to those of the file's template, with descriptions
+    pub fn vars(&self) -> Vec<(String, &'a Vars)> {
+        let mut ret: Vec<_> = self
+            .presets()
+            .map(|(name, preset)| (format!("preset {} vars", name), &preset.vars))
+            .collect();
+        ret.push(("repo vars".into(), &self.repo.vars));
+        ret.extend(self.branch.map(|b| ("branch vars".into(), &b.vars)));
+        ret
+    }
+
+    /// Computed defaults from the repo's presets for vars where is_set
+    /// returns false, as templates still to be rendered
+    pub fn computed_defaults(&self, is_set: impl Fn(&str) -> bool) -> Result<Vars> {
+        // later presets take precedence
+        let mut vars = BTreeMap::new();
+        for (preset_name, preset) in self.presets() {
+            for (name, value) in preset.defaults.iter() {
+                if is_set(name) {
+                    continue;
+                }
+                if !value.is_string() {
+                    bail!(
+                        "default for {} in preset {} is not a string",
+                        name,
+                        preset_name
+                    );
+                }
+                vars.insert(name.into(), value.clone());
+            }
+        }
+        Ok(Vars { vars })
+    }
+}
+
 impl File {
+    /// Convert a File with a repo selector into one File per matching repo
+    fn expand(self, cfg: &Config) -> Result<Vec<Self>> {
+        let selector = match (&self.repos, self.repo.is_empty()) {
+            (None, false) => return Ok(vec![self]),
+            (Some(selector), true) => selector,
+            (None, true) => bail!("file {} has neither repo nor repos", self.path),
+            (Some(_), false) => bail!("file {} has both repo and repos", self.path),
+        };
+        for name in &selector.exclude {
+            if !cfg.repos.contains_key(name) {
+                bail!("excluded repo {} does not exist", name);
+            }
+        }
+        let ret: Vec<Self> = cfg
+            .repos
+            .iter()
+            .filter(|(name, repo)| {
+                repo.tags.contains(&selector.tag) && !selector.exclude.contains(name)
+            })
+            .map(|(name, _)| Self {
+                repo: name.clone(),
+                repos: None,
+                path: self.path.clone(),
+                vars: self.vars.clone(),
+            })
+            .collect();
+        if ret.is_empty() {
+            bail!("no repos match tag {} for file {}", selector.tag, self.path);
+        }
+        Ok(ret)
+    }
+
     /// Look up Repo from Config
     pub fn repo<'a>(&self, cfg: &'a Config) -> Result<&'a Repo> {
         cfg.repos
@@ -100,4 +574,247 @@ impl Vars {
     pub fn to_context(&self) -> Result<tera::Context> {
         Ok(tera::Context::from_serialize(&self.vars)?)
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &serde_yaml::Value)> {
+        self.vars.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.vars.keys().map(|k| k.as_str())
+    }
+
+    /// String values that are Tera templates
+    pub fn templated(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().filter_map(|(k, v)| {
+            let s = v.as_str()?;
+            (s.contains("{{") || s.contains("{%")).then_some((k.as_str(), s))
+        })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.vars.contains_key(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let cfg: Config = serde_yaml::from_str(
+            "
+templates: []
+vars:
+  arches: [amd64]
+repos:
+  a:
+    url: https://example.com/a
+  b:
+    url: https://example.com/b
+    vars:
+      arches: amd64
+    branches:
+      - name: main
+      - name: old
+        vars:
+          arches: [amd64]
+",
+        )
+        .unwrap();
+        let tmpl_cfg: TemplateConfig = serde_yaml::from_str(
+            "
+schema:
+  arches:
+    type: list
+    required: true
+  image:
+    type: string
+    required: true
+files:
+  - repo: a
+    path: x
+    vars:
+      image: quay.io/a
+  - repo: b
+    path: x
+    vars:
+      image: quay.io/b
+  - repo: a
+    path: y
+",
+        )
+        .unwrap();
+        tmpl_cfg.validate_schema().unwrap();
+        tmpl_cfg
+            .validate(&cfg, &tmpl_cfg.files[0], &cfg.target("a", None).unwrap())
+            .unwrap();
+        let target = |branch| cfg.target("b", Some(branch)).unwrap();
+        assert_eq!(
+            format!(
+                "{:#}",
+                tmpl_cfg
+                    .validate(&cfg, &tmpl_cfg.files[1], &target("main"))
+                    .unwrap_err()
+            ),
+            "variable arches from repo vars: expected list, found string"
+        );
+        tmpl_cfg
+            .validate(&cfg, &tmpl_cfg.files[1], &target("old"))
+            .unwrap();
+        assert_eq!(cfg.target("a", None).unwrap().dir(), "a");
+        assert_eq!(cfg.target("b", Some("old")).unwrap().dir(), "b@old");
+        assert!(cfg.target("b", None).is_err());
+        assert_eq!(
+            tmpl_cfg
+                .validate(&cfg, &tmpl_cfg.files[2], &cfg.target("a", None).unwrap())
+                .unwrap_err()
+                .to_string(),
+            "required variable image is not set"
+        );
+    }
+
+    #[test]
+    fn include() {
+        let temp = tempfile::tempdir().unwrap();
+        let write = |path: &str, contents: &str| {
+            let path = temp.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        let config = temp.path().join("config.yaml");
+        write(
+            "config.yaml",
+            "
+include: [repos/*.yaml]
+templates: []
+repos:
+  a:
+    url: https://example.com/a
+vars:
+  x: 1
+",
+        );
+        write(
+            "repos/b.yaml",
+            "
+repos:
+  b:
+    url: https://example.com/b
+vars:
+  y: 2
+",
+        );
+        write("repos/c.yaml", "repos: {c: {url: https://example.com/c}}");
+        let cfg = Config::load(&config).unwrap();
+        assert_eq!(cfg.repos.keys().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(
+            cfg.repos["c"].source.as_deref(),
+            Some(temp.path().join("repos/c.yaml").as_path())
+        );
+        assert_eq!(cfg.vars.names().collect::<Vec<_>>(), ["x", "y"]);
+        assert_eq!(
+            cfg.var_source("y"),
+            Some(temp.path().join("repos/b.yaml").as_path())
+        );
+
+        write("repos/d.yaml", "repos: {b: {url: https://example.com/d}}");
+        assert_eq!(
+            Config::load(&config).unwrap_err().to_string(),
+            format!(
+                "repo b is defined in both {} and {}",
+                temp.path().join("repos/b.yaml").display(),
+                temp.path().join("repos/d.yaml").display()
+            )
+        );
+    }
+
+    #[test]
+    fn presets() {
+        let cfg: Config = serde_yaml::from_str(
+            "
+templates: []
+presets:
+  rust:
+    vars: {library: false, kind: rust}
+    defaults: {package: 'rust-{{ crate }}', srpm: '{{ package }}'}
+  lib:
+    vars: {library: true}
+repos:
+  a:
+    url: https://example.com/a
+    presets: [rust, lib]
+    vars: {crate: a, kind: a}
+  b:
+    url: https://example.com/b
+    presets: [missing]
+",
+        )
+        .unwrap();
+        let target = cfg.target("a", None).unwrap();
+        let mut ctx = tera::Context::new();
+        for (_, vars) in target.vars() {
+            ctx.extend(vars.to_context().unwrap());
+        }
+        assert_eq!(ctx.get("library").unwrap(), true);
+        assert_eq!(ctx.get("kind").unwrap(), "a");
+        let computed = target
+            .computed_defaults(|name| name == "srpm")
+            .unwrap()
+            .to_context()
+            .unwrap();
+        assert_eq!(computed.get("package").unwrap(), "rust-{{ crate }}");
+        assert!(computed.get("srpm").is_none());
+        assert_eq!(
+            cfg.target("b", None).unwrap_err().to_string(),
+            "repo b: no such preset missing"
+        );
+    }
+
+    #[test]
+    fn expand() {
+        let cfg: Config = serde_yaml::from_str(
+            "
+templates: []
+repos:
+  a: {url: https://example.com/a, tags: [rust]}
+  b: {url: https://example.com/b, tags: [rust, go]}
+  c: {url: https://example.com/c}
+",
+        )
+        .unwrap();
+        let expand = |yaml: &str| {
+            serde_yaml::from_str::<File>(yaml)
+                .unwrap()
+                .expand(&cfg)
+                .map(|files| files.into_iter().map(|f| f.repo).collect::<Vec<_>>())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(expand("{repo: c, path: x}").unwrap(), ["c"]);
+        assert_eq!(expand("{repos: {tag: rust}, path: x}").unwrap(), ["a", "b"]);
+        assert_eq!(
+            expand("{repos: {tag: rust, exclude: [a]}, path: x}").unwrap(),
+            ["b"]
+        );
+        assert_eq!(
+            expand("{repos: {tag: go, exclude: [b]}, path: x}").unwrap_err(),
+            "no repos match tag go for file x"
+        );
+        assert_eq!(
+            expand("{repos: {tag: python}, path: x}").unwrap_err(),
+            "no repos match tag python for file x"
+        );
+        assert_eq!(
+            expand("{repos: {tag: rust, exclude: [d]}, path: x}").unwrap_err(),
+            "excluded repo d does not exist"
+        );
+        assert_eq!(
+            expand("{repo: a, repos: {tag: rust}, path: x}").unwrap_err(),
+            "file x has both repo and repos"
+        );
+        assert_eq!(
+            expand("{path: x}").unwrap_err(),
+            "file x has neither repo nor repos"
+        );
+    }
 }